@@ -0,0 +1,167 @@
+use crate::error::{Error, Result};
+use crate::http::HttpClient;
+use reqwest::header;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+/// Treat tokens as expired this many seconds early so they don't run out mid-request
+const TOKEN_EXPIRY_MARGIN_SECS: u64 = 60;
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct AccessToken {
+    access_token: String,
+    /// Unix timestamp (in seconds) at which the token stops being valid
+    expires_at: u64,
+    /// The accounts service and client the token was issued by and for
+    auth_url: String,
+    client_id: String,
+}
+
+impl AccessToken {
+    fn is_expired(&self) -> bool {
+        unix_now() + TOKEN_EXPIRY_MARGIN_SECS >= self.expires_at
+    }
+}
+
+async fn get_access_token(
+    http: &HttpClient,
+    auth_url: &str,
+    client_id: &str,
+    client_secret: &str,
+) -> Result<AccessToken> {
+    let url = format!("{auth_url}/api/token");
+    let (status, content) = http
+        .send_checked(
+            |client| {
+                client
+                    .post(&url)
+                    .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                    .body(format!(
+                        "grant_type=client_credentials&client_id={client_id}&client_secret={client_secret}"
+                    ))
+            },
+            |response| async {
+                let status = response.status();
+                Ok(((status, response.text().await?), false))
+            },
+        )
+        .await?;
+
+    if !status.is_success() {
+        let description = serde_json::from_str::<serde_json::Value>(&content)
+            .ok()
+            .and_then(|json_object| json_object["error_description"].as_str().map(String::from))
+            .unwrap_or(status.to_string());
+        return Err(Error::Auth(description));
+    }
+    let json_object: serde_json::Value = serde_json::from_str(&content)?;
+    let access_token = json_object["access_token"]
+        .as_str()
+        .ok_or(Error::InvalidResponse(
+            "invalid field in token response: `access_token`".to_string(),
+        ))?
+        .to_string();
+    let expires_in = json_object["expires_in"]
+        .as_u64()
+        .ok_or(Error::InvalidResponse(
+            "invalid field in token response: `expires_in`".to_string(),
+        ))?;
+    Ok(AccessToken {
+        access_token,
+        expires_at: unix_now() + expires_in,
+        auth_url: auth_url.to_string(),
+        client_id: client_id.to_string(),
+    })
+}
+
+/// Hands out access tokens, reusing the cached one until it expires
+pub(crate) struct Auth {
+    pub(crate) auth_url: String,
+    client_id: String,
+    client_secret: String,
+    cache_file: Option<PathBuf>,
+    token: Mutex<Option<AccessToken>>,
+}
+
+impl Auth {
+    pub(crate) fn new(auth_url: String, client_id: String, client_secret: String) -> Self {
+        Self {
+            auth_url,
+            client_id,
+            client_secret,
+            cache_file: None,
+            token: Mutex::new(None),
+        }
+    }
+
+    /// Persists tokens to `cache_file`, picking up the one already stored there
+    pub(crate) fn set_cache_file(&mut self, cache_file: PathBuf) {
+        let token = fs::read_to_string(&cache_file)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok());
+        self.token = Mutex::new(token);
+        self.cache_file = Some(cache_file);
+    }
+
+    /// Whether `token` was issued by this accounts service for this client, so that a cached
+    /// token is never sent on behalf of another client or to another host
+    fn issued(&self, token: &AccessToken) -> bool {
+        token.auth_url == self.auth_url && token.client_id == self.client_id
+    }
+
+    /// Returns a valid access token, requesting a new one if the current one has expired or was
+    /// issued for another client
+    pub(crate) async fn access_token(&self, http: &HttpClient) -> Result<String> {
+        let mut token = self.token.lock().await;
+        if let Some(token) = token
+            .as_ref()
+            .filter(|token| !token.is_expired() && self.issued(token))
+        {
+            return Ok(token.access_token.clone());
+        }
+        let new_token = self.request_token(http).await?;
+        let access_token = new_token.access_token.clone();
+        *token = Some(new_token);
+        Ok(access_token)
+    }
+
+    /// Discards the current access token and requests a new one
+    pub(crate) async fn refresh(&self, http: &HttpClient) -> Result<String> {
+        let mut token = self.token.lock().await;
+        let new_token = self.request_token(http).await?;
+        let access_token = new_token.access_token.clone();
+        *token = Some(new_token);
+        Ok(access_token)
+    }
+
+    async fn request_token(&self, http: &HttpClient) -> Result<AccessToken> {
+        log::debug!("Requesting access token...");
+        let token =
+            get_access_token(http, &self.auth_url, &self.client_id, &self.client_secret).await?;
+        if let Some(cache_file) = &self.cache_file {
+            if let Err(err) = store_token(cache_file, &token) {
+                log::warn!("Could not cache access token: {err}");
+            }
+        }
+        Ok(token)
+    }
+}
+
+/// Writes `token` to `cache_file`, creating its directory if needed
+fn store_token(cache_file: &Path, token: &AccessToken) -> Result<()> {
+    if let Some(directory) = cache_file.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    fs::write(cache_file, serde_json::to_string(token)?)?;
+    Ok(())
+}
@@ -0,0 +1,252 @@
+use crate::auth::unix_now;
+use crate::error::Result;
+use crate::provider::CoverMatch;
+use crate::size::SizePolicy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// What a lookup was for, normalized so that differences in case and spacing don't matter
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum LookupKey {
+    Track {
+        title: String,
+        artists: Vec<String>,
+        album: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        release_id: Option<String>,
+        /// The size policy, since results without a matching image are left out
+        #[serde(default)]
+        size: String,
+    },
+    Album {
+        album: String,
+        artist: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        release_id: Option<String>,
+        /// The size policy, since results without a matching image are left out
+        #[serde(default)]
+        size: String,
+    },
+}
+
+impl LookupKey {
+    pub(crate) fn track(title: &str, artists: &[&str], album: &str) -> Self {
+        let mut artists: Vec<_> = artists.iter().map(|artist| normalize(artist)).collect();
+        artists.sort();
+        LookupKey::Track {
+            title: normalize(title),
+            artists,
+            album: normalize(album),
+            release_id: None,
+            size: SizePolicy::default().to_string(),
+        }
+    }
+
+    pub(crate) fn album(album: &str, artist: &str) -> Self {
+        LookupKey::Album {
+            album: normalize(album),
+            artist: normalize(artist),
+            release_id: None,
+            size: SizePolicy::default().to_string(),
+        }
+    }
+
+    /// Tells apart lookups for the same tags that also name a release by its MusicBrainz ID
+    pub(crate) fn with_release_id(mut self, id: Option<&str>) -> Self {
+        match &mut self {
+            LookupKey::Track { release_id, .. } | LookupKey::Album { release_id, .. } => {
+                *release_id = id.map(|id| id.trim().to_lowercase());
+            }
+        }
+        self
+    }
+
+    /// Tells apart lookups with different size policies, which can find different results
+    pub(crate) fn with_size_policy(mut self, size_policy: &SizePolicy) -> Self {
+        match &mut self {
+            LookupKey::Track { size, .. } | LookupKey::Album { size, .. } => {
+                *size = size_policy.to_string();
+            }
+        }
+        self
+    }
+}
+
+fn normalize(value: &str) -> String {
+    value
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// One line of the cache file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    /// The name of the provider that was searched, see `CoverProvider::name`
+    provider: String,
+    key: LookupKey,
+    /// Unix timestamp (in seconds) of the lookup
+    stored_at: u64,
+    /// `None` if nothing matched
+    cover: Option<CoverMatch>,
+}
+
+/// Remembers the outcome of lookups in a file, so that repeated runs don't search again. New
+/// entries are appended as JSON lines; outdated ones are dropped when the file is opened.
+pub struct LookupCache {
+    file: PathBuf,
+    ttl: Duration,
+    refresh: bool,
+    /// Whether new lookups are only kept in memory, see `open_read_only`
+    read_only: bool,
+    entries: Mutex<HashMap<(String, LookupKey), Entry>>,
+}
+
+impl LookupCache {
+    /// Loads the entries in `file` that are younger than `ttl`. A missing file is an empty cache.
+    pub fn open(file: impl Into<PathBuf>, ttl: Duration) -> Result<Self> {
+        let (cache, lines) = Self::load(file.into(), ttl)?;
+        if lines > cache.len() {
+            cache.compact()?;
+        }
+        Ok(cache)
+    }
+
+    /// Like `open`, but never writes to `file`: new lookups are only reused during this run
+    pub fn open_read_only(file: impl Into<PathBuf>, ttl: Duration) -> Result<Self> {
+        let (mut cache, _) = Self::load(file.into(), ttl)?;
+        cache.read_only = true;
+        Ok(cache)
+    }
+
+    /// Reads the cache and counts the lines of `file`
+    fn load(file: PathBuf, ttl: Duration) -> Result<(Self, usize)> {
+        let mut entries = HashMap::new();
+        let mut lines = 0;
+        if file.exists() {
+            let oldest = unix_now().saturating_sub(ttl.as_secs());
+            for line in BufReader::new(fs::File::open(&file)?).lines() {
+                lines += 1;
+                // Skip lines that were cut off or written by another version
+                let Ok(entry) = serde_json::from_str::<Entry>(&line?) else {
+                    continue;
+                };
+                let id = (entry.provider.clone(), entry.key.clone());
+                if entry.stored_at >= oldest {
+                    entries.insert(id, entry);
+                } else {
+                    entries.remove(&id);
+                }
+            }
+        }
+
+        let cache = Self {
+            file,
+            ttl,
+            refresh: false,
+            read_only: false,
+            entries: Mutex::new(entries),
+        };
+        Ok((cache, lines))
+    }
+
+    /// Ignore the stored entries, but still store new lookups
+    pub fn refresh(mut self, refresh: bool) -> Self {
+        self.refresh = refresh;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The stored outcome of a lookup with `provider`: `Some(None)` means that nothing matched
+    pub(crate) fn get(&self, provider: &str, key: &LookupKey) -> Option<Option<CoverMatch>> {
+        if self.refresh {
+            return None;
+        }
+        let oldest = unix_now().saturating_sub(self.ttl.as_secs());
+        let entries = self.entries.lock().unwrap();
+        let entry = entries
+            .get(&(provider.to_string(), key.clone()))
+            .filter(|entry| entry.stored_at >= oldest)?;
+        Some(entry.cover.clone())
+    }
+
+    pub(crate) fn insert(&self, provider: &str, key: LookupKey, cover: Option<CoverMatch>) {
+        let entry = Entry {
+            provider: provider.to_string(),
+            key,
+            stored_at: unix_now(),
+            cover,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if !self.read_only {
+            if let Err(err) = self.append(&entry) {
+                log::warn!("Could not write lookup cache: {err}");
+            }
+        }
+        entries.insert((entry.provider.clone(), entry.key.clone()), entry);
+    }
+
+    fn append(&self, entry: &Entry) -> Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        if let Some(directory) = self.file.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.file)?
+            .write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Rewrites the file with only the current entries
+    fn compact(&self) -> Result<()> {
+        let entries = self.entries.lock().unwrap();
+        let mut content = String::new();
+        for entry in entries.values() {
+            content.push_str(&serde_json::to_string(entry)?);
+            content.push('\n');
+        }
+        let temp_file = self.file.with_extension("tmp");
+        fs::write(&temp_file, content)?;
+        fs::rename(&temp_file, &self.file)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keys_are_normalized() {
+        assert_eq!(
+            LookupKey::track(" Bohemian  Rhapsody", &["Queen", "David Bowie"], "A Night"),
+            LookupKey::track("bohemian rhapsody", &["david bowie", "QUEEN"], "a night ")
+        );
+        assert_ne!(
+            LookupKey::album("A Night at the Opera", "Queen"),
+            LookupKey::album("A Day at the Races", "Queen")
+        );
+        assert_ne!(
+            LookupKey::album("A Night at the Opera", "Queen"),
+            LookupKey::album("A Night at the Opera", "Queen")
+                .with_size_policy(&SizePolicy::Min(5000))
+        );
+    }
+}
@@ -0,0 +1,311 @@
+use crate::auth::Auth;
+use crate::error::{check_status, Error, Result};
+use crate::http::{HttpClient, RetryPolicy};
+use crate::process::DownloadedImage;
+use crate::provider::{CoverMatch, CoverProvider, Lookup, SearchResult, SearchResults};
+use crate::query::{Field, SearchQuery, SearchType};
+use crate::size::{CoverImage, SizePolicy};
+use crate::tags::{AlbumInfo, TrackInfo};
+use async_trait::async_trait;
+use reqwest::{header, StatusCode};
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_AUTH_URL: &str = "https://accounts.spotify.com";
+pub const DEFAULT_API_URL: &str = "https://api.spotify.com";
+
+/// Looks up cover art for tracks through the Spotify Web API
+pub struct SpotifyClient {
+    http: HttpClient,
+    auth: Auth,
+    api_url: String,
+    size_policy: SizePolicy,
+}
+
+impl SpotifyClient {
+    pub fn new(client_id: impl Into<String>, client_secret: impl Into<String>) -> Self {
+        Self {
+            http: HttpClient::new(),
+            auth: Auth::new(
+                DEFAULT_AUTH_URL.to_string(),
+                client_id.into(),
+                client_secret.into(),
+            ),
+            api_url: DEFAULT_API_URL.to_string(),
+            size_policy: SizePolicy::default(),
+        }
+    }
+
+    /// Use a different base URL for the accounts service, e.g. a local mock server
+    pub fn with_auth_url(mut self, auth_url: impl AsRef<str>) -> Self {
+        self.auth.auth_url = auth_url.as_ref().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Use a different base URL for the Web API, e.g. a local mock server
+    pub fn with_api_url(mut self, api_url: impl AsRef<str>) -> Self {
+        self.api_url = api_url.as_ref().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Retry rate limited and failed requests according to `retry_policy`
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.http.set_retry_policy(retry_policy);
+        self
+    }
+
+    /// Send at most `requests_per_second` requests, shared by everything using this client.
+    /// Zero or less means no limit.
+    pub fn with_rate_limit(mut self, requests_per_second: f64) -> Self {
+        self.http.set_rate_limit(requests_per_second);
+        self
+    }
+
+    /// Store the access token in `cache_file` so that it can be reused across runs
+    pub fn with_token_cache(mut self, cache_file: impl Into<PathBuf>) -> Self {
+        self.auth.set_cache_file(cache_file.into());
+        self
+    }
+
+    /// Pick the size of each cover according to `size_policy`. Results that have no image
+    /// matching the policy are skipped.
+    pub fn with_size_policy(mut self, size_policy: SizePolicy) -> Self {
+        self.size_policy = size_policy;
+        self
+    }
+
+    /// Returns a valid access token, requesting a new one if needed
+    pub async fn access_token(&self) -> Result<String> {
+        self.auth.access_token(&self.http).await
+    }
+
+    pub async fn search(
+        &self,
+        track_name: &str,
+        artist_names: &[&str],
+    ) -> Result<serde_json::Value> {
+        let query = SearchQuery::new(SearchType::Track)
+            .filter(Field::Track, track_name)
+            .filters(Field::Artist, artist_names);
+        self.search_with(&query).await
+    }
+
+    /// Searches for an album and returns the raw response, unlike
+    /// `CoverProvider::search_albums`
+    pub async fn search_album_json(
+        &self,
+        album_name: &str,
+        artist_name: &str,
+    ) -> Result<serde_json::Value> {
+        let query = SearchQuery::new(SearchType::Album)
+            .filter(Field::Album, album_name)
+            .filter(Field::Artist, artist_name);
+        self.search_with(&query).await
+    }
+
+    pub async fn search_with(&self, query: &SearchQuery) -> Result<serde_json::Value> {
+        let url = format!(
+            "{api_url}/v1/search?{query}",
+            api_url = self.api_url,
+            query = query.to_query_string()
+        );
+        let url = &url;
+        // `None` if the access token was rejected
+        let send = |access_token: String| {
+            let authorization = format!("Bearer {access_token}");
+            self.http.send_checked(
+                move |client| {
+                    client
+                        .get(url)
+                        .header("Accept", "application/json")
+                        .header("User-Agent", "Rust")
+                        .header(header::AUTHORIZATION, authorization.as_str())
+                },
+                |response| async {
+                    if response.status() == StatusCode::UNAUTHORIZED {
+                        return Ok((None, false));
+                    }
+                    let content = check_status(response)?.text().await?;
+                    Ok((Some(serde_json::from_str(&content)?), false))
+                },
+            )
+        };
+
+        if let Some(res) = send(self.access_token().await?).await? {
+            return Ok(res);
+        }
+        log::debug!("Access token was rejected, refreshing...");
+        send(self.auth.refresh(&self.http).await?)
+            .await?
+            .ok_or(Error::Auth(
+                "the API rejected a freshly issued access token".to_string(),
+            ))
+    }
+
+    pub async fn get_image_url_for_track(
+        &self,
+        track_name: &str,
+        artist_names: &[&str],
+        album_name: &str,
+    ) -> Result<String> {
+        let cover = self
+            .find_track_cover(track_name, artist_names, album_name)
+            .await?;
+        Ok(cover.image.url)
+    }
+
+    pub async fn get_image_url_from_filename(&self, filename: impl AsRef<Path>) -> Result<String> {
+        Ok(self.find_file_cover(filename).await?.image.url)
+    }
+
+    pub async fn get_image_url_for_album(
+        &self,
+        album_name: &str,
+        artist_name: &str,
+    ) -> Result<String> {
+        Ok(self
+            .find_album_cover(album_name, artist_name)
+            .await?
+            .image
+            .url)
+    }
+
+    pub async fn get_image_url_for_tracks(&self, tracks: &[TrackInfo]) -> Result<String> {
+        Ok(self.find_tracks_cover(tracks).await?.image.url)
+    }
+
+    /// Searches with increasingly broad queries (see `track_queries`) and returns the best match
+    /// from the first one that has any results
+    pub async fn find_track_cover(
+        &self,
+        track_name: &str,
+        artist_names: &[&str],
+        album_name: &str,
+    ) -> Result<CoverMatch> {
+        Lookup::Track {
+            track_name,
+            artist_names,
+            album_name,
+            release_id: None,
+        }
+        .find(self, &self.size_policy)
+        .await
+    }
+
+    pub async fn find_file_cover(&self, filename: impl AsRef<Path>) -> Result<CoverMatch> {
+        let track = TrackInfo::from_file(filename)?;
+        self.find_track_cover(&track.title, &track.artist_names(), &track.album)
+            .await
+    }
+
+    /// Like `find_track_cover`, but searches for albums (see `album_queries`)
+    pub async fn find_album_cover(
+        &self,
+        album_name: &str,
+        artist_name: &str,
+    ) -> Result<CoverMatch> {
+        Lookup::Album {
+            album_name,
+            artist_name,
+            release_id: None,
+        }
+        .find(self, &self.size_policy)
+        .await
+    }
+
+    /// Looks up the cover for the album that the given tracks agree on, see `AlbumInfo::from_tracks`
+    pub async fn find_tracks_cover(&self, tracks: &[TrackInfo]) -> Result<CoverMatch> {
+        let album = AlbumInfo::from_tracks(tracks).ok_or(Error::MissingTag("album"))?;
+        self.find_album_cover(&album.album, &album.album_artist)
+            .await
+    }
+
+    /// Downloads an image and makes sure that it is one, see `inspect_image`
+    pub async fn download_image(&self, image_url: &str) -> Result<DownloadedImage> {
+        self.http.download_image(image_url).await
+    }
+}
+
+#[async_trait]
+impl CoverProvider for SpotifyClient {
+    fn name(&self) -> &'static str {
+        "spotify"
+    }
+
+    async fn search_results(
+        &self,
+        query: &SearchQuery,
+        _album_name: &str,
+        size_policy: &SizePolicy,
+    ) -> Result<Option<SearchResults>> {
+        let res = self.search_with(query).await?;
+        let results = match query.search_type() {
+            SearchType::Track => res["tracks"]["items"]
+                .as_array()
+                .ok_or(Error::InvalidResponse(
+                    "`tracks.items` should be an array".to_string(),
+                ))?
+                .iter()
+                .filter_map(track_from_json)
+                .collect::<Vec<_>>(),
+            SearchType::Album => res["albums"]["items"]
+                .as_array()
+                .ok_or(Error::InvalidResponse(
+                    "`albums.items` should be an array".to_string(),
+                ))?
+                .iter()
+                .filter_map(album_from_json)
+                .collect(),
+        };
+        Ok(SearchResults::usable(query, results, size_policy))
+    }
+
+    async fn download_image(&self, image_url: &str) -> Result<DownloadedImage> {
+        self.http.download_image(image_url).await
+    }
+}
+
+/// Reads a track search result, returns `None` for malformed ones
+fn track_from_json(track: &serde_json::Value) -> Option<SearchResult> {
+    let artists = artists_from_json(&track["artists"])?;
+    if artists.is_empty() {
+        return None;
+    }
+
+    Some(SearchResult {
+        id: track["id"].as_str().map(String::from),
+        track: Some(track["name"].as_str()?.to_string()),
+        album: track["album"]["name"].as_str()?.to_string(),
+        artists,
+        images: images_from_json(&track["album"]["images"])?,
+    })
+}
+
+/// Reads an album search result, returns `None` for malformed ones
+fn album_from_json(album: &serde_json::Value) -> Option<SearchResult> {
+    Some(SearchResult {
+        id: album["id"].as_str().map(String::from),
+        track: None,
+        album: album["name"].as_str()?.to_string(),
+        artists: artists_from_json(&album["artists"])?,
+        images: images_from_json(&album["images"])?,
+    })
+}
+
+fn artists_from_json(artists: &serde_json::Value) -> Option<Vec<String>> {
+    artists
+        .as_array()?
+        .iter()
+        .map(|artist| Some(artist["name"].as_str()?.to_string()))
+        .collect()
+}
+
+/// The well-formed entries of an `images` array
+fn images_from_json(images: &serde_json::Value) -> Option<Vec<CoverImage>> {
+    Some(
+        images
+            .as_array()?
+            .iter()
+            .filter_map(CoverImage::from_json)
+            .collect(),
+    )
+}
@@ -0,0 +1,193 @@
+use crate::error::{check_status, Error, Result};
+use crate::http::{HttpClient, RetryPolicy};
+use crate::process::DownloadedImage;
+use crate::provider::{CoverProvider, SearchResult, SearchResults};
+use crate::query::{Field, SearchQuery, SearchType};
+use crate::size::{CoverImage, SizePolicy};
+use async_trait::async_trait;
+
+pub const DEFAULT_DEEZER_URL: &str = "https://api.deezer.com";
+
+/// The fields every album has its cover in, with their size in pixels
+const COVER_SIZES: [(&str, u32); 4] = [
+    ("cover_small", 56),
+    ("cover_medium", 250),
+    ("cover_big", 500),
+    ("cover_xl", 1000),
+];
+
+/// Results to ask for per search
+const SEARCH_LIMIT: usize = 25;
+
+/// The error code Deezer answers with when too many requests were sent
+const QUOTA_EXCEEDED: u64 = 4;
+
+/// Looks up cover art through the public Deezer API, which needs no credentials
+pub struct DeezerClient {
+    http: HttpClient,
+    api_url: String,
+}
+
+impl Default for DeezerClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeezerClient {
+    pub fn new() -> Self {
+        Self {
+            http: HttpClient::new(),
+            api_url: DEFAULT_DEEZER_URL.to_string(),
+        }
+    }
+
+    /// Use a different base URL for the API, e.g. a local mock server
+    pub fn with_api_url(mut self, api_url: impl AsRef<str>) -> Self {
+        self.api_url = api_url.as_ref().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Retry rate limited and failed requests according to `retry_policy`
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.http.set_retry_policy(retry_policy);
+        self
+    }
+
+    /// Send at most `requests_per_second` requests, shared by everything using this client.
+    /// Zero or less means no limit.
+    pub fn with_rate_limit(mut self, requests_per_second: f64) -> Self {
+        self.http.set_rate_limit(requests_per_second);
+        self
+    }
+
+    /// Searches for `query` among the items of type `entity`, e.g. `track`
+    async fn search(&self, entity: &str, query: &str) -> Result<serde_json::Value> {
+        let url = format!(
+            "{}/search/{entity}?q={}&limit={SEARCH_LIMIT}",
+            self.api_url,
+            urlencoding::encode(query)
+        );
+        let res = self
+            .http
+            .send_checked(
+                |client| client.get(&url),
+                |response| async {
+                    let content = check_status(response)?.text().await?;
+                    let res: serde_json::Value = serde_json::from_str(&content)?;
+                    let quota_exceeded = res["error"]["code"].as_u64() == Some(QUOTA_EXCEEDED);
+                    Ok((res, quota_exceeded))
+                },
+            )
+            .await?;
+
+        // Errors come with a successful status
+        let error = &res["error"];
+        if error.is_object() {
+            if error["code"].as_u64() == Some(QUOTA_EXCEEDED) {
+                return Err(Error::RateLimited { retry_after: None });
+            }
+            let message = error["message"].as_str().unwrap_or("unknown error");
+            return Err(Error::InvalidResponse(message.to_string()));
+        }
+        Ok(res)
+    }
+}
+
+#[async_trait]
+impl CoverProvider for DeezerClient {
+    fn name(&self) -> &'static str {
+        "deezer"
+    }
+
+    async fn search_results(
+        &self,
+        query: &SearchQuery,
+        _album_name: &str,
+        size_policy: &SizePolicy,
+    ) -> Result<Option<SearchResults>> {
+        let entity = match query.search_type() {
+            SearchType::Track => "track",
+            SearchType::Album => "album",
+        };
+        let query = deezer_query(query);
+        let res = self.search(entity, &query).await?;
+        let results = res["data"]
+            .as_array()
+            .ok_or(Error::InvalidResponse(
+                "`data` should be an array".to_string(),
+            ))?
+            .iter()
+            .filter_map(result_from_json)
+            .collect();
+        Ok(SearchResults::usable(query, results, size_policy))
+    }
+
+    async fn download_image(&self, image_url: &str) -> Result<DownloadedImage> {
+        self.http.download_image(image_url).await
+    }
+}
+
+/// Reads a track or album, returns `None` for malformed ones and other kinds of items
+fn result_from_json(item: &serde_json::Value) -> Option<SearchResult> {
+    let (track, album) = match item["type"].as_str()? {
+        "track" => (Some(item["title"].as_str()?.to_string()), &item["album"]),
+        "album" => (None, item),
+        _ => return None,
+    };
+    Some(SearchResult {
+        id: item["id"].as_u64().map(|id| id.to_string()),
+        track,
+        album: album["title"].as_str()?.to_string(),
+        artists: vec![item["artist"]["name"].as_str()?.to_string()],
+        images: cover_images(album),
+    })
+}
+
+/// Every size the cover of `album` is available in
+fn cover_images(album: &serde_json::Value) -> Vec<CoverImage> {
+    COVER_SIZES
+        .iter()
+        .filter_map(|&(field, size)| {
+            Some(CoverImage {
+                url: album[field].as_str()?.to_string(),
+                width: Some(size),
+                height: Some(size),
+            })
+        })
+        .collect()
+}
+
+/// Turns `query` into Deezer's advanced search syntax. Quotes can't be escaped, so they are
+/// left out of the values.
+fn deezer_query(query: &SearchQuery) -> String {
+    let terms: Vec<_> = query
+        .fields()
+        .map(|(field, value)| {
+            let field = match field {
+                Field::Track => "track",
+                Field::Artist => "artist",
+                Field::Album => "album",
+            };
+            format!("{field}:\"{}\"", value.replace('"', ""))
+        })
+        .collect();
+    terms.join(" ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn query_uses_advanced_search() {
+        let query = SearchQuery::new(SearchType::Track)
+            .filter(Field::Track, "The \"Wall\"")
+            .filters(Field::Artist, &["Pink Floyd"])
+            .filter(Field::Album, "The Wall");
+        assert_eq!(
+            deezer_query(&query),
+            r#"track:"The Wall" artist:"Pink Floyd" album:"The Wall""#
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use reqwest::StatusCode;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Invalid filetype: {}", .0.display())]
+    InvalidFiletype(PathBuf),
+
+    #[error("Could not read tags: {0}")]
+    Tag(#[from] audiotags::Error),
+
+    #[error("Missing tag: {0}")]
+    MissingTag(&'static str),
+
+    #[error("Unsupported image format")]
+    UnsupportedImage,
+
+    #[error("Invalid image: {0}")]
+    InvalidImage(String),
+
+    #[error("Could not process image: {0}")]
+    Image(#[from] image::ImageError),
+
+    #[error("Authentication failed: {0}")]
+    Auth(String),
+
+    #[error("Rate limited by the API")]
+    RateLimited { retry_after: Option<Duration> },
+
+    #[error("Request to {url} failed with HTTP {status}")]
+    HttpStatus { status: StatusCode, url: String },
+
+    #[error("Request failed: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("Invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Unexpected response: {0}")]
+    InvalidResponse(String),
+
+    #[error("No match found for {0}")]
+    NoMatch(String),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The delay requested through the `Retry-After` header, if given in seconds
+pub(crate) fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs)
+}
+
+/// Turns an unsuccessful response into the matching error
+pub(crate) fn check_status(response: reqwest::Response) -> Result<reqwest::Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return Err(Error::RateLimited {
+            retry_after: retry_after(&response),
+        });
+    }
+    Err(Error::HttpStatus {
+        status,
+        url: response.url().to_string(),
+    })
+}
@@ -0,0 +1,253 @@
+use crate::error::{check_status, retry_after, Error, Result};
+use crate::process::{inspect_image, DownloadedImage};
+use reqwest::{header, StatusCode};
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// How long to wait for a connection to a server
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a request may take in total, including reading the response body
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often and how long to wait before retrying rate limited, failed or dropped requests
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt, so `0` disables retrying
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled on every following one
+    pub base_delay: Duration,
+    /// Upper bound for any single delay, including ones requested through `Retry-After`
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with jitter: half of the delay is fixed, the other half random
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_delay);
+        let random = RandomState::new().build_hasher().finish();
+        delay / 2 + (delay / 2).mul_f64((random % 1000) as f64 / 1000.0)
+    }
+}
+
+/// The longest delay between requests, for rate limits of less than a request a day
+const MAX_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Spaces requests out evenly, so that all requests made through a client stay under a rate
+/// limit even when they come from concurrent tasks
+struct RateLimiter {
+    interval: Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    /// A limiter for `requests_per_second`, or one that never waits if that isn't positive.
+    /// Requests are never held back longer than `MAX_INTERVAL`.
+    fn new(requests_per_second: f64) -> Self {
+        let interval = if requests_per_second > 0.0 {
+            Duration::try_from_secs_f64(1.0 / requests_per_second)
+                .map_or(MAX_INTERVAL, |interval| interval.min(MAX_INTERVAL))
+        } else {
+            Duration::ZERO
+        };
+        Self {
+            interval,
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until the next request may be sent
+    async fn acquire(&self) {
+        let mut next_slot = self.next_slot.lock().await;
+        tokio::time::sleep_until(*next_slot).await;
+        *next_slot = Instant::now() + self.interval;
+    }
+
+    /// Holds back every request for `delay`, e.g. after the API asked to slow down
+    async fn pause(&self, delay: Duration) {
+        let mut next_slot = self.next_slot.lock().await;
+        *next_slot = (*next_slot).max(Instant::now() + delay);
+    }
+}
+
+/// A `reqwest::Client` that retries requests according to a `RetryPolicy` and keeps to the
+/// rate limit of its `RateLimiter`
+pub(crate) struct HttpClient {
+    client: reqwest::Client,
+    retry_policy: RetryPolicy,
+    rate_limiter: RateLimiter,
+}
+
+impl HttpClient {
+    pub(crate) fn new() -> Self {
+        Self {
+            client: client_builder()
+                .build()
+                .expect("The HTTP client should be configurable"),
+            retry_policy: RetryPolicy::default(),
+            rate_limiter: RateLimiter::new(0.0),
+        }
+    }
+
+    /// Retry rate limited and failed requests according to `retry_policy`
+    pub(crate) fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Send at most `requests_per_second` requests, see `RateLimiter::new`
+    pub(crate) fn set_rate_limit(&mut self, requests_per_second: f64) {
+        self.rate_limiter = RateLimiter::new(requests_per_second);
+    }
+
+    /// Sends `user_agent` with every request
+    pub(crate) fn set_user_agent(&mut self, user_agent: &str) {
+        self.client = client_builder()
+            .user_agent(user_agent)
+            .build()
+            .expect("The HTTP client should be configurable");
+    }
+
+    /// Sends the request built by `request` and hands the response to `check`, which reads it
+    /// and tells whether it is a rate limit error anyway, for APIs that report those with a
+    /// successful status. Retries on 429 and such rate limit errors, 5xx, and transient network
+    /// errors, including ones while `check` reads the body. Once out of retries the last
+    /// response is passed to `check` as-is.
+    pub(crate) async fn send_checked<T, F>(
+        &self,
+        request: impl Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+        check: impl Fn(reqwest::Response) -> F,
+    ) -> Result<T>
+    where
+        F: Future<Output = Result<(T, bool)>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let can_retry = attempt < self.retry_policy.max_retries;
+            self.rate_limiter.acquire().await;
+            let delay = match request(&self.client).send().await {
+                Ok(response) if can_retry && response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    let delay = match retry_after(&response) {
+                        Some(retry_after) => retry_after.min(self.retry_policy.max_delay),
+                        None => self.retry_policy.backoff(attempt),
+                    };
+                    // Concurrent requests would only be rate limited as well
+                    self.rate_limiter.pause(delay).await;
+                    delay
+                }
+                Ok(response) if can_retry && response.status().is_server_error() => {
+                    self.retry_policy.backoff(attempt)
+                }
+                Ok(response) => match check(response).await {
+                    Ok((checked, rate_limited)) if !(can_retry && rate_limited) => {
+                        return Ok(checked)
+                    }
+                    Ok(_) => {
+                        let delay = self.retry_policy.backoff(attempt);
+                        self.rate_limiter.pause(delay).await;
+                        delay
+                    }
+                    Err(Error::Http(err)) if can_retry && is_transient(&err) => {
+                        self.retry_policy.backoff(attempt)
+                    }
+                    Err(err) => return Err(err),
+                },
+                Err(err) if can_retry && is_transient(&err) => self.retry_policy.backoff(attempt),
+                Err(err) => return Err(err.into()),
+            };
+
+            attempt += 1;
+            log::warn!(
+                "Request failed, retrying in {:.1}s ({attempt}/{})...",
+                delay.as_secs_f64(),
+                self.retry_policy.max_retries
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Sends the request built by `request` and reads the JSON body of a successful response,
+    /// see `send_checked`
+    pub(crate) async fn send_json(
+        &self,
+        request: impl Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+    ) -> Result<serde_json::Value> {
+        self.send_checked(request, |response| async {
+            let content = check_status(response)?.text().await?;
+            Ok((serde_json::from_str(&content)?, false))
+        })
+        .await
+    }
+
+    /// Downloads an image and makes sure that it is one, see `inspect_image`
+    pub(crate) async fn download_image(&self, image_url: &str) -> Result<DownloadedImage> {
+        let data = self
+            .send_checked(
+                |client| client.get(image_url),
+                |response| async {
+                    let response = check_status(response)?;
+                    let content_type = response
+                        .headers()
+                        .get(header::CONTENT_TYPE)
+                        .and_then(|content_type| content_type.to_str().ok());
+                    if let Some(content_type) = content_type.filter(|content_type| {
+                        !content_type.starts_with("image/")
+                            && !content_type.ends_with("/octet-stream")
+                    }) {
+                        return Err(Error::InvalidImage(format!(
+                            "{image_url} has content type {content_type}"
+                        )));
+                    }
+                    Ok((response.bytes().await?.to_vec(), false))
+                },
+            )
+            .await?;
+        let info = inspect_image(&data)?;
+        Ok(DownloadedImage { data, info })
+    }
+}
+
+/// A client that gives up on servers that don't answer, see `CONNECT_TIMEOUT` and
+/// `REQUEST_TIMEOUT`
+fn client_builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+}
+
+/// Whether a request failed in a way that may not happen again: the server couldn't be
+/// reached, didn't answer in time, or the connection dropped while sending the request or
+/// reading the response
+fn is_transient(err: &reqwest::Error) -> bool {
+    err.is_timeout() || err.is_connect() || err.is_request() || err.is_body() || err.is_decode()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_interval() {
+        assert_eq!(RateLimiter::new(4.0).interval, Duration::from_millis(250));
+        assert_eq!(RateLimiter::new(0.0).interval, Duration::ZERO);
+        assert_eq!(RateLimiter::new(f64::NAN).interval, Duration::ZERO);
+        assert_eq!(RateLimiter::new(f64::INFINITY).interval, Duration::ZERO);
+        assert_eq!(RateLimiter::new(1e-300).interval, MAX_INTERVAL);
+    }
+}
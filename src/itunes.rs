@@ -0,0 +1,184 @@
+use crate::error::{Error, Result};
+use crate::http::{HttpClient, RetryPolicy};
+use crate::process::DownloadedImage;
+use crate::provider::{CoverProvider, SearchResult, SearchResults};
+use crate::query::{SearchQuery, SearchType};
+use crate::size::{CoverImage, SizePolicy};
+use async_trait::async_trait;
+
+pub const DEFAULT_ITUNES_URL: &str = "https://itunes.apple.com";
+pub const DEFAULT_ITUNES_COUNTRY: &str = "US";
+
+/// The sizes that artwork URLs are rewritten to, see `artwork_images`
+const ARTWORK_SIZES: [u32; 4] = [100, 600, 1400, 3000];
+
+/// Results to ask for per search
+const SEARCH_LIMIT: usize = 25;
+
+/// Looks up cover art through the iTunes Search API, which needs no credentials
+pub struct ItunesClient {
+    http: HttpClient,
+    api_url: String,
+    country: String,
+}
+
+impl Default for ItunesClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ItunesClient {
+    pub fn new() -> Self {
+        Self {
+            http: HttpClient::new(),
+            api_url: DEFAULT_ITUNES_URL.to_string(),
+            country: DEFAULT_ITUNES_COUNTRY.to_string(),
+        }
+    }
+
+    /// Use a different base URL for the Search API, e.g. a local mock server
+    pub fn with_api_url(mut self, api_url: impl AsRef<str>) -> Self {
+        self.api_url = api_url.as_ref().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Retry rate limited and failed requests according to `retry_policy`
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.http.set_retry_policy(retry_policy);
+        self
+    }
+
+    /// Send at most `requests_per_second` requests, shared by everything using this client.
+    /// Zero or less means no limit.
+    pub fn with_rate_limit(mut self, requests_per_second: f64) -> Self {
+        self.http.set_rate_limit(requests_per_second);
+        self
+    }
+
+    /// Search the storefront of `country`, a two-letter code like `US` or `GB`
+    pub fn with_country(mut self, country: impl AsRef<str>) -> Self {
+        self.country = country.as_ref().trim().to_string();
+        self
+    }
+
+    /// Searches for `term` among the items of type `entity`, e.g. `song`
+    async fn search(&self, entity: &str, term: &str) -> Result<serde_json::Value> {
+        let url = format!(
+            "{}/search?term={}&media=music&entity={entity}&country={}&limit={SEARCH_LIMIT}",
+            self.api_url,
+            urlencoding::encode(term),
+            urlencoding::encode(&self.country)
+        );
+        self.http
+            .send_json(|client| client.get(&url).header("Accept", "application/json"))
+            .await
+    }
+}
+
+#[async_trait]
+impl CoverProvider for ItunesClient {
+    fn name(&self) -> &'static str {
+        "itunes"
+    }
+
+    async fn search_results(
+        &self,
+        query: &SearchQuery,
+        _album_name: &str,
+        size_policy: &SizePolicy,
+    ) -> Result<Option<SearchResults>> {
+        let entity = match query.search_type() {
+            SearchType::Track => "song",
+            SearchType::Album => "album",
+        };
+        // The Search API has no fields, so all values go into the search term
+        let term = query
+            .fields()
+            .map(|(_, value)| value)
+            .collect::<Vec<_>>()
+            .join(" ");
+        let res = self.search(entity, &term).await?;
+        let results = res["results"]
+            .as_array()
+            .ok_or(Error::InvalidResponse(
+                "`results` should be an array".to_string(),
+            ))?
+            .iter()
+            .filter_map(result_from_json)
+            .collect();
+        Ok(SearchResults::usable(term, results, size_policy))
+    }
+
+    async fn download_image(&self, image_url: &str) -> Result<DownloadedImage> {
+        self.http.download_image(image_url).await
+    }
+}
+
+/// Reads a song or album, returns `None` for malformed ones and other kinds of items
+fn result_from_json(item: &serde_json::Value) -> Option<SearchResult> {
+    let (id, track) = match item["wrapperType"].as_str()? {
+        "track" if item["kind"].as_str() == Some("song") => (
+            &item["trackId"],
+            Some(item["trackName"].as_str()?.to_string()),
+        ),
+        "collection" => (&item["collectionId"], None),
+        _ => return None,
+    };
+    Some(SearchResult {
+        id: id.as_u64().map(|id| id.to_string()),
+        track,
+        album: item["collectionName"].as_str()?.to_string(),
+        artists: vec![item["artistName"].as_str()?.to_string()],
+        images: artwork_images(item["artworkUrl100"].as_str()?),
+    })
+}
+
+/// The artwork in every size of `ARTWORK_SIZES`. Artwork URLs end in the size, e.g.
+/// `100x100bb.jpg`, and the API scales the image to whatever size the URL asks for.
+fn artwork_images(url: &str) -> Vec<CoverImage> {
+    let Some((prefix, suffix)) = url.rsplit_once("100x100") else {
+        return vec![CoverImage {
+            url: url.to_string(),
+            width: None,
+            height: None,
+        }];
+    };
+    ARTWORK_SIZES
+        .iter()
+        .map(|&size| CoverImage {
+            url: format!("{prefix}{size}x{size}{suffix}"),
+            width: Some(size),
+            height: Some(size),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn artwork_urls_are_rewritten_to_each_size() {
+        let images = artwork_images(
+            "https://is1-ssl.mzstatic.com/image/thumb/Music/a1/100x100/100x100bb.jpg",
+        );
+        assert_eq!(
+            images
+                .iter()
+                .map(|image| image.url.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "https://is1-ssl.mzstatic.com/image/thumb/Music/a1/100x100/100x100bb.jpg",
+                "https://is1-ssl.mzstatic.com/image/thumb/Music/a1/100x100/600x600bb.jpg",
+                "https://is1-ssl.mzstatic.com/image/thumb/Music/a1/100x100/1400x1400bb.jpg",
+                "https://is1-ssl.mzstatic.com/image/thumb/Music/a1/100x100/3000x3000bb.jpg",
+            ]
+        );
+        assert_eq!(images[3].width, Some(3000));
+
+        let images = artwork_images("https://example.com/cover.jpg");
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].width, None);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// How the work for an item of a run ended
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "outcome", rename_all = "kebab-case")]
+pub enum Outcome {
+    Found,
+    NoMatch,
+    Error { message: String },
+}
+
+impl Outcome {
+    /// Whether the item needs no more work. Errors are retried.
+    pub fn is_complete(&self) -> bool {
+        !matches!(self, Outcome::Error { .. })
+    }
+}
+
+/// One line of the journal file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    item: PathBuf,
+    #[serde(flatten)]
+    outcome: Outcome,
+}
+
+/// Records the outcome of each item of a recursive run in a file as it finishes, so that an
+/// interrupted run can be resumed. Items are stored by absolute path.
+pub struct Journal {
+    file: PathBuf,
+    entries: Mutex<HashMap<PathBuf, Outcome>>,
+}
+
+impl Journal {
+    /// Loads the outcomes in `file`. A missing file is an empty journal.
+    pub fn open(file: impl Into<PathBuf>) -> Result<Self> {
+        let file = file.into();
+        let mut entries = HashMap::new();
+        if file.exists() {
+            for line in BufReader::new(fs::File::open(&file)?).lines() {
+                // Skip lines that were cut off by a crash
+                let Ok(entry) = serde_json::from_str::<Entry>(&line?) else {
+                    continue;
+                };
+                entries.insert(entry.item, entry.outcome);
+            }
+        }
+        Ok(Self {
+            file,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// The last recorded outcome for `item`
+    pub fn outcome(&self, item: &Path) -> Option<Outcome> {
+        self.entries.lock().unwrap().get(&absolute(item)).cloned()
+    }
+
+    /// Appends the outcome for `item` to the file
+    pub fn record(&self, item: &Path, outcome: Outcome) -> Result<()> {
+        let entry = Entry {
+            item: absolute(item),
+            outcome,
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(directory) = self.file.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.file)?
+            .write_all(line.as_bytes())?;
+        entries.insert(entry.item, entry.outcome);
+        Ok(())
+    }
+
+    /// Forgets the outcomes of the items below `root`, to start over
+    pub fn clear(&self, root: &Path) -> Result<()> {
+        let root = absolute(root);
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|item, _| !item.starts_with(&root));
+        if !self.file.exists() {
+            return Ok(());
+        }
+
+        let mut content = String::new();
+        for (item, outcome) in entries.iter() {
+            let entry = Entry {
+                item: item.clone(),
+                outcome: outcome.clone(),
+            };
+            content.push_str(&serde_json::to_string(&entry)?);
+            content.push('\n');
+        }
+        let temp_file = self.file.with_extension("tmp");
+        fs::write(&temp_file, content)?;
+        fs::rename(&temp_file, &self.file)?;
+        Ok(())
+    }
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or(path.to_path_buf())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn outcomes_survive_reopening() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("journal.jsonl");
+        let music = directory.path().join("music");
+
+        let journal = Journal::open(&file).unwrap();
+        journal.record(&music.join("A"), Outcome::Found).unwrap();
+        journal
+            .record(
+                &music.join("B"),
+                Outcome::Error {
+                    message: "Connection reset".to_string(),
+                },
+            )
+            .unwrap();
+        journal.record(&music.join("C"), Outcome::NoMatch).unwrap();
+        journal.record(&music.join("C"), Outcome::Found).unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&file)
+            .unwrap()
+            .write_all(b"{\"item\": \"/cut")
+            .unwrap();
+
+        let journal = Journal::open(&file).unwrap();
+        assert_eq!(journal.outcome(&music.join("A")), Some(Outcome::Found));
+        assert!(!journal.outcome(&music.join("B")).unwrap().is_complete());
+        assert_eq!(journal.outcome(&music.join("C")), Some(Outcome::Found));
+        assert_eq!(journal.outcome(&music.join("D")), None);
+
+        journal.clear(&music).unwrap();
+        assert_eq!(
+            Journal::open(&file).unwrap().outcome(&music.join("A")),
+            None
+        );
+    }
+}
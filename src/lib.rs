@@ -0,0 +1,38 @@
+//! Find album art for audio files by looking up their tags on Spotify and other providers
+
+mod auth;
+mod cache;
+mod client;
+mod deezer;
+mod error;
+mod http;
+mod itunes;
+mod journal;
+mod logging;
+mod musicbrainz;
+mod process;
+mod provider;
+mod query;
+mod report;
+mod size;
+mod tags;
+mod template;
+
+pub use cache::LookupCache;
+pub use client::{SpotifyClient, DEFAULT_API_URL, DEFAULT_AUTH_URL};
+pub use deezer::{DeezerClient, DEFAULT_DEEZER_URL};
+pub use error::{Error, Result};
+pub use http::RetryPolicy;
+pub use itunes::{ItunesClient, DEFAULT_ITUNES_COUNTRY, DEFAULT_ITUNES_URL};
+pub use journal::{Journal, Outcome};
+pub use logging::{capture_log, print_output, LogMessage, Logger};
+pub use musicbrainz::{MusicBrainzClient, DEFAULT_COVER_ART_URL, DEFAULT_MUSICBRAINZ_URL};
+pub use process::{inspect_image, DownloadedImage, ImageFormat, ImageInfo, Processing};
+pub use provider::{
+    Candidate, CoverFinder, CoverMatch, CoverProvider, SearchResult, SearchResults,
+};
+pub use query::{Field, SearchQuery, SearchType};
+pub use report::{Destination, Report, ReportEntry, ReportFormat, ReportMatch, ReportTags, Status};
+pub use size::{CoverImage, SizePolicy};
+pub use tags::{embed_cover, has_embedded_cover, AlbumInfo, TrackInfo};
+pub use template::{possible_paths, with_extension, OutputTemplate, TemplateFields};
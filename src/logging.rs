@@ -0,0 +1,138 @@
+use crate::auth::unix_now;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::cell::RefCell;
+use std::fs;
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+tokio::task_local! {
+    /// Collects the messages logged by a task instead of printing them, see `capture_log`
+    static CAPTURED_LOG: RefCell<Vec<LogMessage>>;
+}
+
+/// A message held back by `capture_log`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogMessage {
+    /// A diagnostic for the logger
+    Log { level: Level, text: String },
+    /// A line of output for stdout, see `print_output`
+    Output(String),
+}
+
+impl LogMessage {
+    /// Logs or prints the message now
+    pub fn emit(self) {
+        match self {
+            LogMessage::Log { level, text } => log::log!(level, "{text}"),
+            LogMessage::Output(text) => write_output(&text),
+        }
+    }
+}
+
+/// Prints `text` as a line on stdout, regardless of the log level. Within `capture_log` it is
+/// held back like log messages, so that it stays in order with them.
+pub fn print_output(text: impl Into<String>) {
+    let text = text.into();
+    let captured = CAPTURED_LOG
+        .try_with(|captured| captured.borrow_mut().push(LogMessage::Output(text.clone())));
+    if captured.is_err() {
+        write_output(&text);
+    }
+}
+
+fn write_output(text: &str) {
+    // A closed stdout, e.g. when piped into `head`, isn't worth failing over
+    let _ = writeln!(io::stdout().lock(), "{text}");
+}
+
+/// Runs `future` and returns the messages it logged instead of printing them, so that the output
+/// of concurrent tasks can be printed without interleaving
+pub async fn capture_log<T>(future: impl Future<Output = T>) -> (T, Vec<LogMessage>) {
+    CAPTURED_LOG
+        .scope(RefCell::new(Vec::new()), async {
+            let output = future.await;
+            (output, CAPTURED_LOG.with(RefCell::take))
+        })
+        .await
+}
+
+/// Prints messages up to `level` to stderr and writes them to an optional log file, which gets
+/// debug messages too. Messages from other crates are only shown at the trace level.
+pub struct Logger {
+    level: LevelFilter,
+    file: Option<Mutex<fs::File>>,
+}
+
+impl Logger {
+    pub fn new(level: LevelFilter) -> Self {
+        Self { level, file: None }
+    }
+
+    /// Appends to the file at `path`, creating it if needed
+    pub fn with_file(mut self, path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        self.file = Some(Mutex::new(file));
+        Ok(self)
+    }
+
+    /// Makes this the logger of the `log` crate. Fails if there already is one.
+    pub fn init(self) -> Result<(), log::SetLoggerError> {
+        log::set_max_level(self.max_level());
+        log::set_boxed_logger(Box::new(self))
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        match self.file {
+            Some(_) => self.level.max(LevelFilter::Debug),
+            None => self.level,
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let ours = metadata.target().starts_with(env!("CARGO_CRATE_NAME"));
+        metadata.level() <= self.max_level() && (ours || self.level == LevelFilter::Trace)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let level = record.level();
+        let text = record.args().to_string();
+        let captured = CAPTURED_LOG.try_with(|captured| {
+            captured.borrow_mut().push(LogMessage::Log {
+                level,
+                text: text.clone(),
+            })
+        });
+        if captured.is_ok() {
+            return;
+        }
+
+        if level <= self.level {
+            match level {
+                Level::Error => eprintln!("SPOT_IMG_SEARCH: Error: {text}"),
+                Level::Warn => eprintln!("SPOT_IMG_SEARCH: Warning: {text}"),
+                _ => eprintln!("SPOT_IMG_SEARCH: {text}"),
+            }
+        }
+        if let Some(file) = &self.file {
+            let line = format!("{} {level:<5} {text}\n", unix_now());
+            // There is nowhere left to report a failure to write the log
+            let _ = file.lock().unwrap().write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
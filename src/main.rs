@@ -1,21 +1,43 @@
-use anyhow::{anyhow, Result};
-use audiotags;
-use clap::Parser;
-use homedir;
-use edit_distance;
-use reqwest::{self, header};
-use serde_json;
+use anyhow::{anyhow, Context, Result};
+use clap::error::ErrorKind;
+use clap::{ArgAction, CommandFactory, Parser, ValueEnum};
+use log::LevelFilter;
+use spotify_image_search::{
+    capture_log, embed_cover, has_embedded_cover, inspect_image, possible_paths, print_output,
+    with_extension, AlbumInfo, CoverFinder, CoverImage, CoverMatch, CoverProvider, DeezerClient,
+    Destination, DownloadedImage, Error, ImageFormat, ItunesClient, Journal, LogMessage, Logger,
+    LookupCache, MusicBrainzClient, Outcome, OutputTemplate, Processing, Report, ReportEntry,
+    ReportFormat, ReportTags, RetryPolicy, SizePolicy, SpotifyClient, TemplateFields, TrackInfo,
+    DEFAULT_API_URL, DEFAULT_AUTH_URL, DEFAULT_COVER_ART_URL, DEFAULT_DEEZER_URL,
+    DEFAULT_ITUNES_COUNTRY, DEFAULT_ITUNES_URL, DEFAULT_MUSICBRAINZ_URL,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::Path;
 use std::path::PathBuf;
-use thiserror::Error;
-use tokio;
-use urlencoding;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
 use walkdir::WalkDir;
 
-/// Simple program to greet a person
+const EXIT_CODES: &str = "\
+Exit codes:
+  1  Other error
+  2  Invalid arguments
+  3  Could not read the file's tags
+  4  Authentication failed
+  5  Rate limited by the API
+  6  HTTP or network error
+  7  Unexpected response from the API
+  8  No match found
+  9  IO error";
+
+/// Find album art for audio files and save it next to them
 #[derive(Parser, Debug)]
+#[command(after_help = EXIT_CODES)]
 struct Args {
     file: PathBuf,
 
@@ -23,250 +45,1124 @@ struct Args {
     #[arg(short, long)]
     recursive: bool,
 
-    /// Filename for image
+    /// Filename for image, relative to the directory of the audio files. Can contain {album},
+    /// {albumartist}, {artist} and {ext}, and be given several times to write several sizes.
+    /// A .jpg, .png or .webp extension is changed to match the format of the image.
     #[arg(short, long, default_value = "cover.jpg")]
-    output: PathBuf,
+    output: Vec<OutputTemplate>,
+
+    /// Size of the image for each --output, in the same order: largest, smallest, original,
+    /// closest:<px> or min:<px>. Outputs without a size of their own use the last one given.
+    #[arg(short, long, default_value = "largest")]
+    size: Vec<SizePolicy>,
 
     /// Force overwriting the existing output file
     #[arg(short, long)]
     force: bool,
+
+    /// Search for the album instead of the track, once per directory when used with --recursive
+    #[arg(short, long)]
+    album: bool,
+
+    /// Look up the images and show where they would be written, without downloading or writing
+    /// anything, including the lookup cache
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+
+    /// Also embed the image as front cover art into the audio files' tags
+    #[arg(short, long)]
+    embed: bool,
+
+    /// What --embed does with audio files that already have cover art
+    #[arg(long, value_enum, default_value_t = ExistingArt::Skip)]
+    existing_art: ExistingArt,
+
+    /// Scale images down so that neither side is larger than this many pixels
+    #[arg(long, value_name = "PX")]
+    max_size: Option<u32>,
+
+    /// Re-encode images as jpeg, png or webp. WebP is always lossless and can't be embedded.
+    #[arg(long)]
+    format: Option<ImageFormat>,
+
+    /// JPEG quality when re-encoding, from 1 to 100
+    #[arg(long, default_value_t = 90, value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: u8,
+
+    /// Re-encode images to remove EXIF and other metadata
+    #[arg(long)]
+    strip_metadata: bool,
+
+    /// Where to look for covers, in order. Later providers are only asked when the earlier
+    /// ones have no close match.
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "spotify")]
+    providers: Vec<Provider>,
+
+    /// File with the Spotify client ID. Without it, the SPOTIFY_CLIENT_ID environment variable
+    /// is used, which can also be set in a .env file in the current directory or one of its
+    /// parents, and then ~/.config/spotify-image-search/client_id.
+    #[arg(long)]
+    client_id_file: Option<PathBuf>,
+
+    /// File with the Spotify client secret. Without it, SPOTIFY_CLIENT_SECRET is used in the
+    /// same way, and then ~/.config/spotify-image-search/client_secret.
+    #[arg(long)]
+    client_secret_file: Option<PathBuf>,
+
+    /// Base URL of the Spotify accounts service [default: https://accounts.spotify.com]
+    #[arg(long, env = "SPOTIFY_AUTH_URL")]
+    auth_url: Option<String>,
+
+    /// Base URL of the Spotify Web API [default: https://api.spotify.com]
+    #[arg(long, env = "SPOTIFY_API_URL")]
+    api_url: Option<String>,
+
+    /// Base URL of the MusicBrainz API [default: https://musicbrainz.org]
+    #[arg(long, env = "MUSICBRAINZ_URL")]
+    musicbrainz_url: Option<String>,
+
+    /// Base URL of the Cover Art Archive [default: https://coverartarchive.org]
+    #[arg(long, env = "COVER_ART_ARCHIVE_URL")]
+    cover_art_url: Option<String>,
+
+    /// Base URL of the iTunes Search API [default: https://itunes.apple.com]
+    #[arg(long, env = "ITUNES_URL")]
+    itunes_url: Option<String>,
+
+    /// Two-letter code of the iTunes Store country to search, e.g. GB
+    #[arg(long, default_value = DEFAULT_ITUNES_COUNTRY)]
+    itunes_country: String,
+
+    /// Base URL of the Deezer API [default: https://api.deezer.com]
+    #[arg(long, env = "DEEZER_URL")]
+    deezer_url: Option<String>,
+
+    /// How many times to retry rate limited or failed requests
+    #[arg(long, default_value_t = 5)]
+    max_retries: u32,
+
+    /// Longest time to wait before a retry, in seconds
+    #[arg(long, default_value_t = 60)]
+    max_retry_delay: u64,
+
+    /// How many files or directories to look up at the same time with --recursive
+    #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: u16,
+
+    /// Most requests per second to send, shared by all jobs. 0 disables the limit.
+    #[arg(long, default_value_t = 10.0, value_parser = parse_rate_limit)]
+    rate_limit: f64,
+
+    /// Search again instead of using the results of earlier lookups, and don't store new ones
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Search again instead of using the results of earlier lookups, but store the new ones
+    #[arg(long, conflicts_with = "no_cache")]
+    refresh_cache: bool,
+
+    /// How many days the results of lookups are reused for
+    #[arg(long, default_value_t = 30)]
+    cache_ttl: u64,
+
+    /// Skip the directories and files that an earlier --recursive run over the same directory
+    /// already found a cover or no match for
+    #[arg(long, requires = "recursive")]
+    resume: bool,
+
+    /// Write what was looked up, found and written for every file (or directory with --album)
+    /// to PATH, as json, ndjson or csv
+    #[arg(long, num_args = 2, value_names = ["FORMAT", "PATH"])]
+    report: Vec<String>,
+
+    /// Show more details, including HTTP requests with -vv
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Only show errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Also append the messages, including the details shown with -v, to this file
+    #[arg(long)]
+    log_file: Option<PathBuf>,
 }
 
-async fn get_access_token(client_id: &str, client_secret: &str) -> Result<String> {
-    let client = reqwest::Client::new();
-    let response = client
-        .post("https://accounts.spotify.com/api/token")
-        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-        .body(format!(
-            "grant_type=client_credentials&client_id={client_id}&client_secret={client_secret}"
-        ))
-        .send()
-        .await?;
-
-    let content = response.text().await?;
-    let json_object: serde_json::Value = serde_json::from_str(&content)?;
-    let access_token = json_object["access_token"]
-        .as_str()
-        .ok_or(anyhow!("Error: invalid field in response: `access_token`"))?
-        .to_string();
-    Ok(access_token)
+impl Args {
+    /// Checks what clap can't check by itself, failing like clap does with exit code 2
+    fn validate(&self) -> Result<(), clap::Error> {
+        if self.size.len() > self.output.len() {
+            return Err(Args::command().error(
+                ErrorKind::TooManyValues,
+                "every --size needs its own --output",
+            ));
+        }
+        if self.embed && self.format == Some(ImageFormat::Webp) {
+            return Err(Args::command().error(
+                ErrorKind::ArgumentConflict,
+                "WebP images can't be embedded, use --format jpeg or png",
+            ));
+        }
+        self.report()?;
+        Ok(())
+    }
+
+    /// The format and path of the --report, if one was asked for
+    fn report(&self) -> Result<Option<(ReportFormat, &str)>, clap::Error> {
+        match &self.report[..] {
+            [] => Ok(None),
+            [format, path] => {
+                let format = format.parse().map_err(|err: String| {
+                    Args::command().error(ErrorKind::InvalidValue, format!("--report: {err}"))
+                })?;
+                Ok(Some((format, path)))
+            }
+            _ => {
+                Err(Args::command()
+                    .error(ErrorKind::TooManyValues, "--report can only be given once"))
+            }
+        }
+    }
 }
 
-async fn search(
-    access_token: &str,
-    track_name: &str,
-    artist_names: &Vec<&str>,
-) -> Result<serde_json::Value> {
-    let track_name_encoded = urlencoding::encode(&track_name);
-    let client = reqwest::Client::new();
-    let response = client
-        .get(format!(
-            "https://api.spotify.com/v1/search?q=track%3A{track_name_encoded}%20artist%3A{artist}&type=track",
-            artist = artist_names[0],
-
-        ))
-        .header("Accept", "application/json")
-        .header("User-Agent", "Rust")
-        .header(header::AUTHORIZATION, format!("Bearer {access_token}"))
-        .send()
-        .await?;
-    let content = response.text().await?;
-
-    Ok(serde_json::from_str(&content)?)
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Provider {
+    /// The Spotify Web API. Needs a client ID and secret, see --client-id-file.
+    Spotify,
+    /// The Cover Art Archive, finding releases by their MusicBrainz ID tag or by searching
+    /// MusicBrainz. Sends at most one request per second to MusicBrainz.
+    #[value(name = "musicbrainz")]
+    MusicBrainz,
+    /// The iTunes Search API, with artwork of up to 3000x3000 pixels. Needs no credentials.
+    Itunes,
+    /// The Deezer API, with covers of up to 1000x1000 pixels. Needs no credentials.
+    Deezer,
 }
 
-fn calculate_average_artist_names_distance(a: &Vec<&str>, b: &Vec<&str>) -> usize {
-    let num_artists = a.len();
-    let num_found_artists = b.len();
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ExistingArt {
+    /// Leave files that already have cover art untouched
+    Skip,
+    /// Replace the existing cover art
+    Replace,
+}
 
-    let (larger, smaller) = if num_artists > num_found_artists {
-        (a, b)
-    } else {
-        (b, a)
-    };
+/// Parses --rate-limit, which has to leave a representable delay between requests
+fn parse_rate_limit(value: &str) -> Result<f64, String> {
+    let rate_limit: f64 = value
+        .parse()
+        .map_err(|_| format!("`{value}` is not a number"))?;
+    if !rate_limit.is_finite() || rate_limit < 0.0 {
+        return Err(format!("`{value}` is not a positive number or 0"));
+    }
+    if rate_limit > 0.0 && Duration::try_from_secs_f64(1.0 / rate_limit).is_err() {
+        return Err(format!("`{value}` is too low"));
+    }
+    Ok(rate_limit)
+}
 
-    let mut total_distance = 0usize;
-    for outer_artist_name in smaller.iter() {
-        let mut min_distance: Option<usize> = None;
-        for inner_artist_name in larger.iter() {
-            let distance = edit_distance::edit_distance(outer_artist_name, inner_artist_name);
-            min_distance = match min_distance {
-                Some(min_distance) => Some(min_distance.min(distance)),
-                None => Some(distance),
-            };
-        }
-        total_distance += min_distance.expect("There should be at least one artist for the track");
+/// Reads a Spotify credential from the file given on the command line, then the environment
+/// variable `env_var`, then `config_file`. Returns `None` if none of them has it.
+fn read_credential(
+    file_arg: Option<&Path>,
+    env_var: &str,
+    config_file: impl AsRef<Path>,
+) -> Result<Option<String>> {
+    let non_empty = |value: String| Some(value.trim().to_string()).filter(|v| !v.is_empty());
+    if let Some(file) = file_arg {
+        let value = fs::read_to_string(file)
+            .with_context(|| format!("Could not read {}", file.display()))?;
+        return Ok(non_empty(value));
     }
+    if let Some(value) = std::env::var(env_var).ok().and_then(non_empty) {
+        return Ok(Some(value));
+    }
+    match fs::read_to_string(config_file) {
+        Ok(value) => Ok(non_empty(value)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
 
-    total_distance / num_found_artists
+/// Picks the base URL from the command line/environment, then the config file, then the default
+fn resolve_base_url(arg: Option<String>, config_file: impl AsRef<Path>, default: &str) -> String {
+    arg.or_else(|| {
+        fs::read_to_string(config_file)
+            .ok()
+            .map(|url| url.trim().to_string())
+    })
+    .filter(|url| !url.is_empty())
+    .unwrap_or(default.to_string())
 }
 
-async fn get_image_url_for_track(
-    access_token: &str,
-    track_name: &str,
-    artist_names: &Vec<&str>,
-    album_name: &str,
-) -> Result<String> {
-    let res = search(&access_token, track_name, artist_names).await?;
-
-    let mut tracks = res["tracks"]["items"]
-        .as_array()
-        .ok_or(anyhow!("Results should be an array"))?
-        .to_owned();
-    tracks.sort_by_key(|found_track| {
-        let found_track_name = found_track["name"]
-            .as_str()
-            .expect("Track name should be a string");
-        let found_track_artist_names: Vec<_> = found_track["artists"]
-            .as_array()
-            .expect("Track artists should be an array")
-            .iter()
-            .map(|artist| {
-                artist["name"]
-                    .as_str()
-                    .expect("Artist name should be a string")
-            })
-            .collect();
-        let found_track_album_name = found_track["album"]["name"]
-            .as_str()
-            .expect("Album name should be a string");
+impl Provider {
+    /// Sets up the provider with its part of the command line and config
+    fn create(&self, args: &Args, config_home: &Path) -> Result<Box<dyn CoverProvider>> {
+        let retry_policy = RetryPolicy {
+            max_retries: args.max_retries,
+            max_delay: Duration::from_secs(args.max_retry_delay),
+            ..Default::default()
+        };
+        match self {
+            Provider::Spotify => {
+                let client_id = read_credential(
+                    args.client_id_file.as_deref(),
+                    "SPOTIFY_CLIENT_ID",
+                    config_home.join("client_id"),
+                )?;
+                let client_secret = read_credential(
+                    args.client_secret_file.as_deref(),
+                    "SPOTIFY_CLIENT_SECRET",
+                    config_home.join("client_secret"),
+                )?;
+                let (Some(client_id), Some(client_secret)) = (client_id, client_secret) else {
+                    return Err(Error::Auth(format!(
+                        "no Spotify client ID and secret found. Set SPOTIFY_CLIENT_ID and \
+                         SPOTIFY_CLIENT_SECRET in the environment or a .env file, pass \
+                         --client-id-file and --client-secret-file, or save them to {} and {}. \
+                         To search without them, use e.g. --providers deezer,itunes,musicbrainz",
+                        config_home.join("client_id").display(),
+                        config_home.join("client_secret").display()
+                    ))
+                    .into());
+                };
+                let auth_url = resolve_base_url(
+                    args.auth_url.clone(),
+                    config_home.join("auth_url"),
+                    DEFAULT_AUTH_URL,
+                );
+                let api_url = resolve_base_url(
+                    args.api_url.clone(),
+                    config_home.join("api_url"),
+                    DEFAULT_API_URL,
+                );
+                let mut client = SpotifyClient::new(client_id, client_secret)
+                    .with_auth_url(auth_url)
+                    .with_api_url(api_url)
+                    .with_retry_policy(retry_policy)
+                    .with_rate_limit(args.rate_limit);
+                // --dry-run writes nothing, not even the access token
+                if !args.dry_run {
+                    client = client.with_token_cache(config_home.join("token.json"));
+                }
+                Ok(Box::new(client))
+            }
+            Provider::MusicBrainz => {
+                let api_url = args.musicbrainz_url.as_deref();
+                let cover_art_url = args.cover_art_url.as_deref();
+                let client = MusicBrainzClient::new()
+                    .with_api_url(api_url.unwrap_or(DEFAULT_MUSICBRAINZ_URL))
+                    .with_cover_art_url(cover_art_url.unwrap_or(DEFAULT_COVER_ART_URL))
+                    .with_retry_policy(retry_policy)
+                    .with_rate_limit(args.rate_limit);
+                Ok(Box::new(client))
+            }
+            Provider::Itunes => {
+                let client = ItunesClient::new()
+                    .with_api_url(args.itunes_url.as_deref().unwrap_or(DEFAULT_ITUNES_URL))
+                    .with_country(&args.itunes_country)
+                    .with_retry_policy(retry_policy)
+                    .with_rate_limit(args.rate_limit);
+                Ok(Box::new(client))
+            }
+            Provider::Deezer => {
+                let client = DeezerClient::new()
+                    .with_api_url(args.deezer_url.as_deref().unwrap_or(DEFAULT_DEEZER_URL))
+                    .with_retry_policy(retry_policy)
+                    .with_rate_limit(args.rate_limit);
+                Ok(Box::new(client))
+            }
+        }
+    }
+}
 
-        let track_name_distance = edit_distance::edit_distance(track_name, found_track_name);
-        let artist_name_distance = calculate_average_artist_names_distance(artist_names, &found_track_artist_names);
-        let album_name_disatnce = edit_distance::edit_distance(album_name, found_track_album_name);
+/// Maps each kind of failure to its own exit code, see `EXIT_CODES`
+fn exit_code(err: &anyhow::Error) -> u8 {
+    match err.downcast_ref::<Error>() {
+        Some(Error::InvalidFiletype(_) | Error::Tag(_) | Error::MissingTag(_)) => 3,
+        Some(Error::Auth(_)) => 4,
+        Some(Error::RateLimited { .. }) => 5,
+        Some(Error::HttpStatus { .. } | Error::Http(_)) => 6,
+        Some(
+            Error::Json(_)
+            | Error::InvalidResponse(_)
+            | Error::UnsupportedImage
+            | Error::InvalidImage(_)
+            | Error::Image(_),
+        ) => 7,
+        Some(Error::NoMatch(_)) => 8,
+        Some(Error::Io(_)) => 9,
+        None if err.is::<std::io::Error>() => 9,
+        None => 1,
+    }
+}
 
-        track_name_distance + artist_name_distance + album_name_disatnce
-    });
+#[tokio::main]
+async fn main() -> ExitCode {
+    // Variables that are already set take precedence over the ones in .env
+    let dotenv = dotenvy::dotenv();
+    let args = Args::parse();
+    if let Err(err) = args.validate() {
+        err.exit();
+    }
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    let logger = match &args.log_file {
+        Some(log_file) => Logger::new(level).with_file(log_file),
+        None => Ok(Logger::new(level)),
+    };
+    match logger {
+        Ok(logger) => logger.init().expect("No other logger is set"),
+        Err(err) => {
+            eprintln!("SPOT_IMG_SEARCH: Error: Could not open the log file: {err}");
+            return ExitCode::from(9);
+        }
+    }
+    if let Err(err) = dotenv {
+        if !err.not_found() {
+            log::warn!("Could not read .env: {err}");
+        }
+    }
 
-    let track = if tracks.len() <= 1 {
-        &tracks[0]
-    } else {
-        let mut to_return: Option<&serde_json::Value> = None;
-        for track in tracks.iter() {
-            if track["album"]["name"] == serde_json::Value::String(album_name.to_string()) {
-                to_return = Some(track);
-                break;
-            }
+    let code = match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            log::error!("{err}");
+            ExitCode::from(exit_code(&err))
         }
-        match to_return {
-            Some(track) => track,
-            None => &tracks[0],
+    };
+    log::logger().flush();
+    code
+}
+
+async fn run(args: Args) -> Result<()> {
+    let config_home = homedir::my_home()?
+        .ok_or(anyhow!("Could not find the home directory"))?
+        .join(".config/spotify-image-search");
+    let outputs: Vec<_> = args
+        .output
+        .iter()
+        .enumerate()
+        .map(|(i, output)| {
+            let size = args.size.get(i).or(args.size.last()).copied();
+            (output.clone(), size.unwrap_or_default())
+        })
+        .collect();
+
+    let mut seen = HashSet::new();
+    let providers = args
+        .providers
+        .iter()
+        .filter(|provider| seen.insert(**provider))
+        .map(|provider| provider.create(&args, &config_home))
+        .collect::<Result<_>>()?;
+    let mut finder = CoverFinder::new(providers).with_size_policy(outputs[0].1);
+    if !args.no_cache {
+        let ttl = Duration::from_secs(args.cache_ttl.saturating_mul(24 * 60 * 60));
+        let file = config_home.join("lookup_cache.jsonl");
+        let lookup_cache = if args.dry_run {
+            LookupCache::open_read_only(file, ttl)?
+        } else {
+            LookupCache::open(file, ttl)?
+        };
+        let lookup_cache = lookup_cache.refresh(args.refresh_cache);
+        finder = finder.with_lookup_cache(lookup_cache);
+    }
+
+    let processing = Processing {
+        max_size: args.max_size,
+        format: args.format,
+        quality: args.quality,
+        strip_metadata: args.strip_metadata,
+    };
+
+    // Only recursive runs keep a journal, to be able to --resume them
+    let journal = if args.recursive && args.file.is_dir() {
+        let journal = Journal::open(config_home.join("journal.jsonl"))?;
+        if !args.resume && !args.dry_run {
+            journal.clear(&args.file)?;
         }
+        Some(journal)
+    } else {
+        None
     };
 
-    let images = track["album"]["images"]
-        .as_array()
-        .ok_or(anyhow!("Invalid images array"))?;
-    let image_url = images[0]["url"]
-        .as_str()
-        .ok_or(anyhow!("Invalid image url"))?;
+    let report = match args.report()? {
+        Some((format, path)) => Some(Report::create(path, format)?),
+        None => None,
+    };
 
-    Ok(image_url.to_string())
+    Runner {
+        args,
+        outputs,
+        processing,
+        finder,
+        journal,
+        report,
+    }
+    .run()
+    .await
 }
 
-#[derive(Error, Debug)]
-#[error("Invalid Filetype")]
-pub struct InvalidFiletype;
-
-async fn get_image_url_from_filename(
-    access_token: &str,
-    filename: impl AsRef<Path>,
-) -> Result<String> {
-    let tag = match audiotags::Tag::new().read_from_path(filename) {
-        Ok(tag) => tag,
-        Err(_) => return Err(anyhow::Error::new(InvalidFiletype)),
-    };
-    let track_name = tag.title().ok_or(anyhow!("Invalid song title"))?;
-    let artist_names: Vec<_> = tag
-        .artist()
-        .ok_or(anyhow!("Invalid song artists"))?
-        .split(", ")
-        .collect();
-    let album_name = tag
-        .album_title()
-        .ok_or(anyhow!("Invalid song album name"))?;
+/// Carries out the lookups, downloads and writes for one invocation
+struct Runner {
+    args: Args,
+    /// Each image file to write, relative to the directory of the audio files, and its size
+    outputs: Vec<(OutputTemplate, SizePolicy)>,
+    processing: Processing,
+    finder: CoverFinder,
+    journal: Option<Journal>,
+    report: Option<Report>,
+}
 
-    let image_url =
-        get_image_url_for_track(&access_token, track_name, &artist_names, album_name).await?;
-    return Ok(image_url);
+/// What a job leaves for `Runner::run` to print and report
+struct Finished {
+    result: Result<Outcome>,
+    entries: Vec<ReportEntry>,
+    messages: Vec<LogMessage>,
 }
 
-fn log(msg: impl AsRef<str>) {
-    println!("SPOT_IMG_SEARCH: {}", msg.as_ref());
+enum Lookup {
+    Track(TrackInfo),
+    Album(AlbumInfo),
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+/// A search and the audio files to embed its cover into
+struct Search {
+    /// The audio file, or the directory for album lookups
+    source: PathBuf,
+    lookup: Lookup,
+    embed_into: Vec<PathBuf>,
+    message: String,
+}
 
-    let config_home = homedir::my_home()?.unwrap().join(".config/spotify-image-search");
-    let client_id_file = config_home.join("client_id");
-    let client_secret_file = config_home.join("client_secret");
-
-    let client_id = fs::read_to_string(client_id_file)?.trim().to_string();
-    let client_secret = fs::read_to_string(client_secret_file)?.trim().to_string();
-    let access_token = get_access_token(&client_id, &client_secret).await?;
-
-    if args.file.is_dir() {
-        if args.recursive {
-            for entry in WalkDir::new(&args.file) {
-                let filepath = entry.unwrap().path().to_path_buf();
-                let image_file_path = filepath.parent().unwrap().join(&args.output);
-                if !args.force {
-                    if image_file_path.exists() {
-                        continue;
+/// Image files to write and the searches that can provide their cover. The searches run in
+/// order until one of them finds a match, except that searches with files to embed into always
+/// run.
+struct Job {
+    /// What the journal records the outcome under: the directory for album lookups, the first
+    /// image file for track lookups
+    item: PathBuf,
+    targets: Vec<(PathBuf, SizePolicy)>,
+    searches: Vec<Search>,
+    /// Whether existing image files may be overwritten
+    force: bool,
+    /// Whether a failed search moves on to the next one instead of failing the job
+    skip_failures: bool,
+}
+
+impl Runner {
+    /// Plans the jobs, then runs up to --jobs of them at the same time. Each job's output is
+    /// printed once it is done, in the order the jobs were planned. In recursive runs a failed
+    /// job doesn't stop the others.
+    async fn run(self) -> Result<()> {
+        let jobs: Vec<_> = self
+            .plan()?
+            .into_iter()
+            .filter(|job| !self.already_done(job))
+            .collect();
+        let total = jobs.len();
+        let runner = Arc::new(self);
+        let semaphore = Arc::new(Semaphore::new(runner.args.jobs.into()));
+        let handles = jobs
+            .into_iter()
+            .map(|job| {
+                let runner = runner.clone();
+                let semaphore = semaphore.clone();
+                tokio::spawn(async move {
+                    let _permit = semaphore.acquire_owned().await;
+                    let ((result, entries), messages) = capture_log(async {
+                        let item = job.item.clone();
+                        let mut entries = Vec::new();
+                        let result = runner.run_job(job, &mut entries).await;
+                        runner.record(&item, &result);
+                        (result, entries)
+                    })
+                    .await;
+                    Finished {
+                        result,
+                        entries,
+                        messages,
                     }
+                })
+            })
+            .collect();
+
+        let result = runner.finish_jobs(handles, total).await;
+        match &runner.report {
+            // Also write the report when a job failed, to show which one
+            Some(report) => result.and(report.finish().map_err(Into::into)),
+            None => result,
+        }
+    }
+
+    /// Prints and reports the results of the jobs in order. In recursive runs failed jobs are
+    /// counted, otherwise the first failure cancels the remaining jobs.
+    async fn finish_jobs(&self, handles: Vec<JoinHandle<Finished>>, total: usize) -> Result<()> {
+        let mut handles = handles.into_iter();
+        let mut failed = 0;
+        let mut first_error = None;
+        while let Some(handle) = handles.next() {
+            let finished = handle.await?;
+            finished.messages.into_iter().for_each(LogMessage::emit);
+            let reported = match &self.report {
+                Some(report) => finished
+                    .entries
+                    .into_iter()
+                    .try_for_each(|entry| report.add(entry)),
+                None => Ok(()),
+            };
+            match reported.map_err(Into::into).and(finished.result) {
+                Ok(_) => {}
+                Err(err) if self.args.recursive => {
+                    failed += 1;
+                    first_error.get_or_insert(err);
                 }
-                if !filepath.is_dir() {
-                    log("Searching for image...");
-                    match get_image_url_from_filename(&access_token, &filepath).await {
-                        Ok(image_url) => {
-                            log(format!("Found image: {}", image_url));
-                            let image_data = reqwest::get(image_url).await?.bytes().await?;
-
-                            let mut image_file = fs::File::create(&image_file_path)?;
-                            log(format!("Writing to file: {}", image_file_path.into_os_string().into_string().unwrap()));
-                            image_file.write_all(&image_data)?;
-                        }
-                        Err(_) => {
-                            continue;
-                        }
-                    }
+                Err(err) => {
+                    handles.for_each(|handle| handle.abort());
+                    return Err(err);
                 }
             }
-        } else {
+        }
+        match first_error {
+            Some(err) => Err(err.context(format!(
+                "{failed} of {total} lookups failed, run again with --resume to retry them"
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether --resume was given and an earlier run finished the job
+    fn already_done(&self, job: &Job) -> bool {
+        let Some(journal) = self.journal.as_ref().filter(|_| self.args.resume) else {
+            return false;
+        };
+        match journal.outcome(&job.item) {
+            Some(outcome) if outcome.is_complete() => {
+                log::info!(
+                    "Skipping {}: it was done in an earlier run",
+                    job.item.display()
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Writes the outcome of a job to the journal, and in recursive runs logs why it failed
+    fn record(&self, item: &Path, result: &Result<Outcome>) {
+        let outcome = match result {
+            Ok(outcome) => outcome.clone(),
+            Err(err) => {
+                if self.args.recursive {
+                    log::error!("Failed: {}: {err}", item.display());
+                }
+                Outcome::Error {
+                    message: err.to_string(),
+                }
+            }
+        };
+        let Some(journal) = &self.journal else {
+            return;
+        };
+        if self.args.dry_run {
+            return;
+        }
+        if let Err(err) = journal.record(item, outcome) {
+            log::warn!("Could not write journal: {err}");
+        }
+    }
+
+    fn plan(&self) -> Result<Vec<Job>> {
+        if !self.args.file.is_dir() {
+            return Ok(vec![self.plan_file()?]);
+        }
+        if !self.args.recursive {
             return Err(anyhow!(
                 "Cannot provide directory unless --recursive,-r is specified"
             ));
         }
-    } else {
-        let image_file_path = &args.file.parent().unwrap().join(&args.output);
-        log("Searching for image...");
-        let image_url = get_image_url_from_filename(&access_token, &args.file).await?;
-        log(format!("Found image: {}", image_url));
-        let image_data = reqwest::get(image_url).await?.bytes().await?;
-        let mut image_file = if args.force {
-            fs::File::create(&image_file_path)?
+        if self.args.album {
+            Ok(self.plan_albums())
+        } else {
+            Ok(self.plan_tracks())
+        }
+    }
+
+    fn plan_file(&self) -> Result<Job> {
+        let file = &self.args.file;
+        let directory = file.parent().unwrap();
+        let track = TrackInfo::from_file(file)?;
+        let album = AlbumInfo::from_tracks(std::slice::from_ref(&track))
+            .ok_or(Error::MissingTag("album"))?;
+        let (fields, lookup) = if self.args.album {
+            (TemplateFields::from_album(&album), Lookup::Album(album))
         } else {
-            fs::File::create_new(&image_file_path)?
+            (TemplateFields::from_track(&track), Lookup::Track(track))
+        };
+        // When embedding, existing image files are left alone instead of being an error
+        let targets = if self.args.embed {
+            self.targets(directory, &fields, &HashSet::new())
+        } else {
+            self.all_targets(directory, &fields)
         };
-        log(format!("Writing to file: {}", image_file_path.clone().into_os_string().into_string().unwrap()));
-        image_file.write_all(&image_data)?;
-    };
 
-    Ok(())
-}
+        Ok(Job {
+            item: file.clone(),
+            targets,
+            searches: vec![Search {
+                source: file.clone(),
+                lookup,
+                embed_into: self.needs_embedding(std::slice::from_ref(file)),
+                message: "Searching for image...".to_string(),
+            }],
+            force: self.args.force,
+            skip_failures: false,
+        })
+    }
+
+    /// One job per directory
+    fn plan_albums(&self) -> Vec<Job> {
+        let mut claimed = HashSet::new();
+        let mut jobs = Vec::new();
+        for (directory, files) in files_by_directory(&self.args.file) {
+            let tracks: Vec<_> = files.iter().filter_map(|file| read_track(file)).collect();
+            let Some(album) = AlbumInfo::from_tracks(&tracks) else {
+                if !tracks.is_empty() {
+                    log::info!(
+                        "Skipping {}: none of its files has an album tag",
+                        directory.display()
+                    );
+                }
+                continue;
+            };
+            let fields = TemplateFields::from_album(&album);
+            let targets = self.targets(&directory, &fields, &claimed);
+            let embed_into = self.needs_embedding(&files);
+            if targets.is_empty() && embed_into.is_empty() {
+                continue;
+            }
+
+            claimed.extend(targets.iter().map(|(path, _)| path.clone()));
+            jobs.push(Job {
+                item: directory.clone(),
+                targets,
+                searches: vec![Search {
+                    source: directory.clone(),
+                    lookup: Lookup::Album(album),
+                    embed_into,
+                    message: format!("Searching for album image for {}...", directory.display()),
+                }],
+                force: true,
+                skip_failures: true,
+            });
+        }
+        jobs
+    }
+
+    /// One job per set of image files, searching with each file that would get them until one
+    /// has a match
+    fn plan_tracks(&self) -> Vec<Job> {
+        let mut claimed = HashSet::new();
+        let mut jobs: Vec<Job> = Vec::new();
+        let mut job_for_targets: HashMap<Vec<PathBuf>, usize> = HashMap::new();
+        for entry in WalkDir::new(&self.args.file).sort_by_file_name() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    log::warn!("Could not read directory: {err}");
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let filepath = entry.path();
+            let Some(track) = read_track(filepath) else {
+                continue;
+            };
+            let directory = filepath.parent().unwrap();
+            let fields = TemplateFields::from_track(&track);
+            let all_targets = self.all_targets(directory, &fields);
+
+            let key: Vec<_> = all_targets.iter().map(|(path, _)| path.clone()).collect();
+            let item = key[0].clone();
+            let index = *job_for_targets.entry(key).or_insert_with(|| {
+                let targets = self.targets(directory, &fields, &claimed);
+                claimed.extend(targets.iter().map(|(path, _)| path.clone()));
+                jobs.push(Job {
+                    item,
+                    targets,
+                    searches: Vec::new(),
+                    force: true,
+                    skip_failures: true,
+                });
+                jobs.len() - 1
+            });
+            jobs[index].searches.push(Search {
+                source: filepath.to_path_buf(),
+                lookup: Lookup::Track(track),
+                embed_into: self.needs_embedding(&[filepath.to_path_buf()]),
+                message: "Searching for image...".to_string(),
+            });
+        }
+
+        jobs.retain(|job| {
+            !job.targets.is_empty()
+                || job
+                    .searches
+                    .iter()
+                    .any(|search| !search.embed_into.is_empty())
+        });
+        jobs
+    }
+
+    /// Runs the searches of a job. With `skip_failures`, a failed search only fails the job if
+    /// no other search provided the images, or if it had files to embed into.
+    async fn run_job(&self, job: Job, entries: &mut Vec<ReportEntry>) -> Result<Outcome> {
+        let mut targets = job.targets;
+        // Downloaded images by URL, so that files sharing a cover only fetch it once
+        let mut images = HashMap::new();
+        let mut found = false;
+        let mut missed_images = None;
+        let mut missed_embeds = None;
+        for search in job.searches {
+            let tags = match &search.lookup {
+                Lookup::Track(track) => ReportTags::from(track),
+                Lookup::Album(album) => ReportTags::from(album),
+            };
+            let mut entry = ReportEntry::new(&search.source, tags);
+            if targets.is_empty() && search.embed_into.is_empty() {
+                entries.push(entry);
+                continue;
+            }
+
+            log::info!("{}", search.message);
+            let cover = match &search.lookup {
+                Lookup::Track(track) => self.finder.find_track_cover(track).await,
+                Lookup::Album(album) => self.finder.find_album_cover(album).await,
+            };
+            let cover = match cover {
+                Ok(cover) => cover,
+                Err(err) => {
+                    entry.search_failed(&err);
+                    entries.push(entry);
+                    match err {
+                        Error::NoMatch(_) if job.skip_failures => continue,
+                        err if job.skip_failures => {
+                            log::warn!("Search failed: {err}");
+                            if search.embed_into.is_empty() {
+                                missed_images.get_or_insert(err);
+                            } else {
+                                missed_embeds.get_or_insert(err);
+                            }
+                            continue;
+                        }
+                        err => return Err(err.into()),
+                    }
+                }
+            };
+            entry.found(&cover);
+            let applied = self
+                .apply(
+                    &cover,
+                    &targets,
+                    &search.embed_into,
+                    job.force,
+                    &mut images,
+                    &mut entry,
+                )
+                .await;
+            if let Err(err) = &applied {
+                entry.failed(err);
+            }
+            entries.push(entry);
+            applied?;
+            targets.clear();
+            found = true;
+        }
+
+        match (missed_embeds, missed_images) {
+            (Some(err), _) => Err(err.into()),
+            (None, Some(err)) if !found => Err(err.into()),
+            _ if found => Ok(Outcome::Found),
+            _ => Ok(Outcome::NoMatch),
+        }
+    }
+
+    /// Every image file for the audio files in `directory`. If the format is only known after
+    /// downloading, the paths may still contain `{ext}` or change their image extension.
+    fn all_targets(&self, directory: &Path, fields: &TemplateFields) -> Vec<(PathBuf, SizePolicy)> {
+        self.outputs
+            .iter()
+            .map(|(output, size)| {
+                let path = directory.join(output.render(fields));
+                match self.processing.format {
+                    Some(format) => (with_extension(&path, format.extension()), *size),
+                    None => (path, *size),
+                }
+            })
+            .collect()
+    }
+
+    /// The image files for the audio files in `directory` that are not `claimed` by another job
+    /// and either don't exist yet or may be overwritten
+    fn targets(
+        &self,
+        directory: &Path,
+        fields: &TemplateFields,
+        claimed: &HashSet<PathBuf>,
+    ) -> Vec<(PathBuf, SizePolicy)> {
+        self.all_targets(directory, fields)
+            .into_iter()
+            .filter(|(path, _)| {
+                if claimed.contains(path) {
+                    return false;
+                }
+                if !self.args.force && any_exists(path) {
+                    log::info!(
+                        "Skipping {}: it already exists, use --force to overwrite it",
+                        path.display()
+                    );
+                    return false;
+                }
+                true
+            })
+            .collect()
+    }
+
+    /// The audio files among `files` that should get the cover embedded
+    fn needs_embedding(&self, files: &[PathBuf]) -> Vec<PathBuf> {
+        if !self.args.embed {
+            return Vec::new();
+        }
+        files
+            .iter()
+            .filter(|file| match has_embedded_cover(file) {
+                Ok(true) if self.args.existing_art == ExistingArt::Skip => {
+                    log::info!(
+                        "Not embedding into {}: it already has cover art, use --existing-art \
+                         replace to replace it",
+                        file.display()
+                    );
+                    false
+                }
+                Ok(_) => true,
+                // Not an audio file, which `read_track` reports
+                Err(_) => false,
+            })
+            .cloned()
+            .collect()
+    }
 
-#[cfg(test)]
-mod test {
-    use anyhow::Result;
-    use walkdir::WalkDir;
+    /// Downloads the cover, then writes it to the `targets` and embeds it into `embed_into`.
+    /// With --dry-run this only reports what would be done.
+    async fn apply(
+        &self,
+        cover: &CoverMatch,
+        targets: &[(PathBuf, SizePolicy)],
+        embed_into: &[PathBuf],
+        force: bool,
+        images: &mut HashMap<String, DownloadedImage>,
+        entry: &mut ReportEntry,
+    ) -> Result<()> {
+        if self.args.dry_run {
+            print_plan(cover, targets, embed_into, force);
+            for (image_file_path, size) in targets {
+                if let Some(image) = size.select(&cover.images) {
+                    let destination = Destination::new(image_file_path, false, image);
+                    entry.destinations.push(destination);
+                }
+            }
+            for file in embed_into {
+                entry
+                    .destinations
+                    .push(Destination::new(file, true, &cover.image));
+            }
+            return Ok(());
+        }
 
-    #[test]
-    fn walk_dir() -> Result<()> {
-        for entry in WalkDir::new("/home/composer3/Music") {
-            println!("{}", entry?.path().display());
+        for (image_file_path, size) in targets {
+            let Some(image) = size.select(&cover.images) else {
+                log::warn!(
+                    "No {size} image for {cover}, skipping {}",
+                    image_file_path.display()
+                );
+                continue;
+            };
+            log::info!("Found image: {image}");
+            let processed = self.processed(cover, image, images).await?;
+            let image_file_path =
+                with_extension(image_file_path, processed.info.format.extension());
+            if let Some(directory) = image_file_path.parent() {
+                fs::create_dir_all(directory)?;
+            }
+            log::info!("Writing to file: {}", image_file_path.display());
+            write_atomically(&image_file_path, &processed.data, force)?;
+            entry
+                .destinations
+                .push(Destination::new(image_file_path, false, image).with_size(&processed.info));
+        }
+        if !embed_into.is_empty() {
+            let processed = self.processed(cover, &cover.image, images).await?;
+            for file in embed_into {
+                log::info!("Embedding into: {}", file.display());
+                embed_cover(file, &processed.data)?;
+                entry
+                    .destinations
+                    .push(Destination::new(file, true, &cover.image).with_size(&processed.info));
+            }
         }
         Ok(())
     }
+
+    /// Downloads and processes `image` of `cover`
+    async fn processed(
+        &self,
+        cover: &CoverMatch,
+        image: &CoverImage,
+        images: &mut HashMap<String, DownloadedImage>,
+    ) -> Result<DownloadedImage> {
+        let downloaded = match images.get(&image.url) {
+            Some(downloaded) => downloaded,
+            None => {
+                let downloaded = self.finder.download_image(cover, &image.url).await?;
+                log::debug!(
+                    "Downloaded {}x{} {} image",
+                    downloaded.info.width,
+                    downloaded.info.height,
+                    downloaded.info.format
+                );
+                images.entry(image.url.clone()).or_insert(downloaded)
+            }
+        };
+        if self.processing.is_noop() {
+            return Ok(downloaded.clone());
+        }
+        let data = self.processing.apply(&downloaded.data)?;
+        let info = inspect_image(&data)?;
+        Ok(DownloadedImage { data, info })
+    }
+}
+
+/// Reads the tags of a file found with --recursive, logging why if it is skipped
+fn read_track(file: &Path) -> Option<TrackInfo> {
+    match TrackInfo::from_file(file) {
+        Ok(track) => Some(track),
+        Err(Error::InvalidFiletype(_)) => {
+            log::debug!("Skipping {}: not an audio file", file.display());
+            None
+        }
+        Err(err) => {
+            log::info!("Skipping {}: {err}", file.display());
+            None
+        }
+    }
+}
+
+/// Whether the file at `path` exists, with any image extension if it can still change
+fn any_exists(path: &Path) -> bool {
+    possible_paths(path).iter().any(|path| path.exists())
+}
+
+/// Writes `data` to a temporary file next to `path` and then moves it into place, so that `path`
+/// is never left half written. Without `overwrite` an existing file is an error, like with
+/// `File::create_new`.
+fn write_atomically(path: &Path, data: &[u8], overwrite: bool) -> io::Result<()> {
+    let file_name = path.file_name().ok_or(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "not a file path",
+    ))?;
+    let temp_path = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+
+    let mut temp_file = fs::File::create(&temp_path)?;
+    let result = temp_file
+        .write_all(data)
+        .and_then(|()| temp_file.sync_all())
+        .and_then(|()| {
+            if overwrite {
+                return fs::rename(&temp_path, path);
+            }
+            // A hard link fails if `path` exists, unlike a rename
+            match fs::hard_link(&temp_path, path) {
+                Err(err) if err.kind() != io::ErrorKind::AlreadyExists => {
+                    // Not every filesystem supports hard links
+                    if path.exists() {
+                        Err(io::Error::from(io::ErrorKind::AlreadyExists))
+                    } else {
+                        fs::rename(&temp_path, path)
+                    }
+                }
+                result => result,
+            }
+        });
+    if temp_path.exists() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Files below `root`, grouped by the directory they are in
+fn files_by_directory(root: &Path) -> BTreeMap<PathBuf, Vec<PathBuf>> {
+    let mut directories: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for entry in WalkDir::new(root).sort_by_file_name() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                log::warn!("Could not read directory: {err}");
+                continue;
+            }
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        if let Some(directory) = entry.path().parent() {
+            directories
+                .entry(directory.to_path_buf())
+                .or_default()
+                .push(entry.path().to_path_buf());
+        }
+    }
+    directories
+}
+
+/// Prints what --dry-run would do with `cover` on stdout
+fn print_plan(
+    cover: &CoverMatch,
+    targets: &[(PathBuf, SizePolicy)],
+    embed_into: &[PathBuf],
+    force: bool,
+) {
+    print_output(format!("Match: {cover}"));
+    for (image_file_path, size) in targets {
+        let Some(image) = size.select(&cover.images) else {
+            log::warn!("No {size} image for {}", image_file_path.display());
+            continue;
+        };
+        let note = match (any_exists(image_file_path), force) {
+            (false, _) => "",
+            (true, true) => " (exists, would be overwritten)",
+            (true, false) => " (exists, would not be overwritten without --force)",
+        };
+        print_output(format!("Image: {image}"));
+        print_output(format!("Destination: {}{note}", image_file_path.display()));
+    }
+    if !embed_into.is_empty() {
+        print_output(format!("Image: {}", cover.image));
+    }
+    for file in embed_into {
+        let note = match has_embedded_cover(file) {
+            Ok(true) => " (existing art would be replaced)",
+            _ => "",
+        };
+        print_output(format!("Embed into: {}{note}", file.display()));
+    }
 }
@@ -0,0 +1,362 @@
+use crate::error::{check_status, Error, Result};
+use crate::http::{HttpClient, RetryPolicy};
+use crate::process::DownloadedImage;
+use crate::provider::{CoverProvider, SearchResult, SearchResults};
+use crate::query::{Field, SearchQuery, SearchType};
+use crate::size::{CoverImage, SizePolicy};
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use std::collections::HashMap;
+
+pub const DEFAULT_MUSICBRAINZ_URL: &str = "https://musicbrainz.org";
+pub const DEFAULT_COVER_ART_URL: &str = "https://coverartarchive.org";
+
+/// MusicBrainz asks every client to name itself and a way to contact its authors
+const USER_AGENT: &str = concat!(
+    env!("CARGO_PKG_NAME"),
+    "/",
+    env!("CARGO_PKG_VERSION"),
+    " ( https://github.com/pianocomposer321/spotify-image-search )"
+);
+
+/// Results to ask MusicBrainz for per search
+const SEARCH_LIMIT: usize = 10;
+
+/// Releases to check for cover art per search, since each one takes a request
+const MAX_RELEASES: usize = 10;
+
+/// The most requests per second that MusicBrainz allows
+const MUSICBRAINZ_RATE_LIMIT: f64 = 1.0;
+
+/// Finds releases through the MusicBrainz API and their covers in the Cover Art Archive
+pub struct MusicBrainzClient {
+    http: HttpClient,
+    cover_art_http: HttpClient,
+    api_url: String,
+    cover_art_url: String,
+}
+
+impl Default for MusicBrainzClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MusicBrainzClient {
+    pub fn new() -> Self {
+        let mut http = HttpClient::new();
+        http.set_user_agent(USER_AGENT);
+        http.set_rate_limit(MUSICBRAINZ_RATE_LIMIT);
+        let mut cover_art_http = HttpClient::new();
+        cover_art_http.set_user_agent(USER_AGENT);
+        Self {
+            http,
+            cover_art_http,
+            api_url: DEFAULT_MUSICBRAINZ_URL.to_string(),
+            cover_art_url: DEFAULT_COVER_ART_URL.to_string(),
+        }
+    }
+
+    /// Use a different base URL for the MusicBrainz API, e.g. a local mock server
+    pub fn with_api_url(mut self, api_url: impl AsRef<str>) -> Self {
+        self.api_url = api_url.as_ref().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Use a different base URL for the Cover Art Archive, e.g. a local mock server
+    pub fn with_cover_art_url(mut self, cover_art_url: impl AsRef<str>) -> Self {
+        self.cover_art_url = cover_art_url.as_ref().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Retry rate limited and failed requests according to `retry_policy`, for MusicBrainz and
+    /// the Cover Art Archive alike
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.http.set_retry_policy(retry_policy.clone());
+        self.cover_art_http.set_retry_policy(retry_policy);
+        self
+    }
+
+    /// Send at most `requests_per_second` requests, shared by everything using this client.
+    /// Zero or less means no limit, except that MusicBrainz itself never gets more than one
+    /// request per second.
+    pub fn with_rate_limit(mut self, requests_per_second: f64) -> Self {
+        let musicbrainz_rate_limit = if requests_per_second > 0.0 {
+            requests_per_second.min(MUSICBRAINZ_RATE_LIMIT)
+        } else {
+            MUSICBRAINZ_RATE_LIMIT
+        };
+        self.http.set_rate_limit(musicbrainz_rate_limit);
+        self.cover_art_http.set_rate_limit(requests_per_second);
+        self
+    }
+
+    /// Identify as `user_agent`, which should include contact details, see
+    /// https://musicbrainz.org/doc/MusicBrainz_API/Rate_Limiting
+    pub fn with_user_agent(mut self, user_agent: &str) -> Self {
+        self.http.set_user_agent(user_agent);
+        self.cover_art_http.set_user_agent(user_agent);
+        self
+    }
+
+    /// Runs a search on the `entity` endpoint, e.g. `recording`
+    async fn search(&self, entity: &str, query: &str) -> Result<serde_json::Value> {
+        let url = format!(
+            "{}/ws/2/{entity}?query={}&fmt=json&limit={SEARCH_LIMIT}",
+            self.api_url,
+            urlencoding::encode(query)
+        );
+        self.http
+            .send_json(|client| client.get(&url).header("Accept", "application/json"))
+            .await
+    }
+
+    /// Keeps the results whose release has a front cover, and adds its images. Releases from
+    /// `album_name` are checked first.
+    async fn with_covers(
+        &self,
+        mut found: Vec<(String, SearchResult)>,
+        album_name: &str,
+    ) -> Result<Vec<SearchResult>> {
+        found.sort_by_key(|(_, result)| result.album != album_name);
+        let mut covers: HashMap<String, Vec<CoverImage>> = HashMap::new();
+        for (release_id, _) in &found {
+            if covers.len() == MAX_RELEASES {
+                break;
+            }
+            if !covers.contains_key(release_id) {
+                let images = self.front_cover(release_id).await?;
+                covers.insert(release_id.clone(), images);
+            }
+        }
+
+        Ok(found
+            .into_iter()
+            .filter_map(|(release_id, result)| {
+                let images = covers.get(&release_id)?;
+                if images.is_empty() {
+                    return None;
+                }
+                Some(SearchResult {
+                    images: images.clone(),
+                    ..result
+                })
+            })
+            .collect())
+    }
+
+    /// Every size of the front cover of a release, or none if it doesn't have one. Thumbnails
+    /// are at most their nominal size; the original is left without dimensions.
+    async fn front_cover(&self, release_id: &str) -> Result<Vec<CoverImage>> {
+        let url = format!(
+            "{}/release/{}",
+            self.cover_art_url,
+            urlencoding::encode(release_id)
+        );
+        let Some(res) = get_json(&self.cover_art_http, &url, &[StatusCode::NOT_FOUND]).await?
+        else {
+            return Ok(Vec::new());
+        };
+        let front = res["images"].as_array().and_then(|images| {
+            images
+                .iter()
+                .find(|image| image["front"].as_bool() == Some(true))
+        });
+        let Some(front) = front else {
+            return Ok(Vec::new());
+        };
+
+        let mut images = Vec::new();
+        if let Some(url) = front["image"].as_str() {
+            images.push(CoverImage {
+                url: url.to_string(),
+                width: None,
+                height: None,
+            });
+        }
+        for size in [250, 500, 1200] {
+            if let Some(url) = front["thumbnails"][size.to_string()].as_str() {
+                images.push(CoverImage {
+                    url: url.to_string(),
+                    width: Some(size),
+                    height: None,
+                });
+            }
+        }
+        Ok(images)
+    }
+}
+
+#[async_trait]
+impl CoverProvider for MusicBrainzClient {
+    fn name(&self) -> &'static str {
+        "musicbrainz"
+    }
+
+    async fn search_results(
+        &self,
+        query: &SearchQuery,
+        album_name: &str,
+        size_policy: &SizePolicy,
+    ) -> Result<Option<SearchResults>> {
+        let lucene = lucene_query(query);
+        let found = match query.search_type() {
+            SearchType::Track => self.search("recording", &lucene).await?["recordings"]
+                .as_array()
+                .ok_or(Error::InvalidResponse(
+                    "`recordings` should be an array".to_string(),
+                ))?
+                .iter()
+                .flat_map(recording_from_json)
+                .collect(),
+            SearchType::Album => self.search("release", &lucene).await?["releases"]
+                .as_array()
+                .ok_or(Error::InvalidResponse(
+                    "`releases` should be an array".to_string(),
+                ))?
+                .iter()
+                .filter_map(|release| {
+                    let result = release_from_json(release)?;
+                    Some((result.id.clone()?, result))
+                })
+                .collect(),
+        };
+        let results = self.with_covers(found, album_name).await?;
+        Ok(SearchResults::usable(lucene, results, size_policy))
+    }
+
+    async fn find_release(
+        &self,
+        release_id: &str,
+        size_policy: &SizePolicy,
+    ) -> Result<Option<SearchResults>> {
+        let url = format!(
+            "{}/ws/2/release/{}?inc=artist-credits&fmt=json",
+            self.api_url,
+            urlencoding::encode(release_id)
+        );
+        // An ID that MusicBrainz doesn't know or rejects is left to the search
+        let missing = [StatusCode::NOT_FOUND, StatusCode::BAD_REQUEST];
+        let Some(release) = get_json(&self.http, &url, &missing).await? else {
+            log::debug!("MusicBrainz doesn't know the release {release_id}");
+            return Ok(None);
+        };
+        let Some(result) = release_from_json(&release) else {
+            return Err(Error::InvalidResponse(format!(
+                "release {release_id} is missing its title or artists"
+            )));
+        };
+
+        let found = vec![(release_id.to_string(), result)];
+        let results = self.with_covers(found, "").await?;
+        Ok(SearchResults::usable(
+            format!("reid:{release_id}"),
+            results,
+            size_policy,
+        ))
+    }
+
+    async fn download_image(&self, image_url: &str) -> Result<DownloadedImage> {
+        self.cover_art_http.download_image(image_url).await
+    }
+}
+
+/// Sends a GET request for JSON, returns `None` if the response has one of the `missing` statuses
+async fn get_json(
+    http: &HttpClient,
+    url: &str,
+    missing: &[StatusCode],
+) -> Result<Option<serde_json::Value>> {
+    http.send_checked(
+        |client| client.get(url).header("Accept", "application/json"),
+        |response| async move {
+            if missing.contains(&response.status()) {
+                return Ok((None, false));
+            }
+            let content = check_status(response)?.text().await?;
+            Ok((Some(serde_json::from_str(&content)?), false))
+        },
+    )
+    .await
+}
+
+/// The query in MusicBrainz' Lucene syntax, e.g. `recording:"Bohemian Rhapsody" AND artist:"Queen"`
+fn lucene_query(query: &SearchQuery) -> String {
+    let terms: Vec<_> = query
+        .fields()
+        .map(|(field, value)| {
+            let field = match field {
+                Field::Track => "recording",
+                Field::Artist => "artist",
+                Field::Album => "release",
+            };
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("{field}:\"{value}\"")
+        })
+        .collect();
+    terms.join(" AND ")
+}
+
+/// One result per release of a recording, with the release's ID. Malformed recordings have
+/// none.
+fn recording_from_json(recording: &serde_json::Value) -> Vec<(String, SearchResult)> {
+    let (Some(title), Some(artists)) = (
+        recording["title"].as_str(),
+        artists_from_json(&recording["artist-credit"]),
+    ) else {
+        return Vec::new();
+    };
+    let Some(releases) = recording["releases"].as_array() else {
+        return Vec::new();
+    };
+
+    releases
+        .iter()
+        .filter_map(|release| {
+            let result = SearchResult {
+                id: recording["id"].as_str().map(String::from),
+                track: Some(title.to_string()),
+                album: release["title"].as_str()?.to_string(),
+                artists: artists.clone(),
+                images: Vec::new(),
+            };
+            Some((release["id"].as_str()?.to_string(), result))
+        })
+        .collect()
+}
+
+/// Reads a release, without its images. Returns `None` for malformed ones.
+fn release_from_json(release: &serde_json::Value) -> Option<SearchResult> {
+    Some(SearchResult {
+        id: release["id"].as_str().map(String::from),
+        track: None,
+        album: release["title"].as_str()?.to_string(),
+        artists: artists_from_json(&release["artist-credit"])?,
+        images: Vec::new(),
+    })
+}
+
+fn artists_from_json(artist_credit: &serde_json::Value) -> Option<Vec<String>> {
+    let artists: Vec<_> = artist_credit
+        .as_array()?
+        .iter()
+        .map(|credit| Some(credit["name"].as_str()?.to_string()))
+        .collect::<Option<_>>()?;
+    Some(artists).filter(|artists| !artists.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lucene_values_are_quoted() {
+        let query = SearchQuery::new(SearchType::Track)
+            .filter(Field::Track, "The \"Wall\"")
+            .filters(Field::Artist, &["Pink Floyd", "AC\\DC"]);
+        assert_eq!(
+            lucene_query(&query),
+            r#"recording:"The \"Wall\"" AND artist:"Pink Floyd" AND artist:"AC\\DC""#
+        );
+    }
+}
@@ -0,0 +1,324 @@
+use crate::error::{Error, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType as ResizeFilter;
+use image::DynamicImage;
+use std::fmt;
+use std::str::FromStr;
+
+/// A format covers can be re-encoded in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    /// Always lossless, so the quality setting does not apply
+    Webp,
+}
+
+impl ImageFormat {
+    fn from_image_format(format: image::ImageFormat) -> Option<Self> {
+        match format {
+            image::ImageFormat::Jpeg => Some(ImageFormat::Jpeg),
+            image::ImageFormat::Png => Some(ImageFormat::Png),
+            image::ImageFormat::WebP => Some(ImageFormat::Webp),
+            _ => None,
+        }
+    }
+
+    /// The usual file extension, as used for `{ext}` in output templates
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Png => "png",
+            ImageFormat::Webp => "webp",
+        }
+    }
+
+    fn to_image_format(self) -> image::ImageFormat {
+        match self {
+            ImageFormat::Jpeg => image::ImageFormat::Jpeg,
+            ImageFormat::Png => image::ImageFormat::Png,
+            ImageFormat::Webp => image::ImageFormat::WebP,
+        }
+    }
+}
+
+/// What a downloaded image turned out to be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The bytes of a downloaded image that passed `inspect_image`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadedImage {
+    pub data: Vec<u8>,
+    pub info: ImageInfo,
+}
+
+/// Checks that `image_data` is a complete JPEG, PNG or WebP image by sniffing its magic bytes
+/// and decoding it
+pub fn inspect_image(image_data: &[u8]) -> Result<ImageInfo> {
+    let format = image::guess_format(image_data)
+        .ok()
+        .and_then(ImageFormat::from_image_format)
+        .ok_or(Error::InvalidImage(
+            "not a JPEG, PNG or WebP image".to_string(),
+        ))?;
+    // The JPEG decoder fills in missing data instead of failing, so check for the end marker
+    let tail = &image_data[image_data.len().saturating_sub(32)..];
+    if format == ImageFormat::Jpeg && !tail.windows(2).any(|marker| marker == b"\xff\xd9") {
+        return Err(Error::InvalidImage("truncated JPEG".to_string()));
+    }
+    let image = image::load_from_memory_with_format(image_data, format.to_image_format())
+        .map_err(|err| Error::InvalidImage(err.to_string()))?;
+
+    Ok(ImageInfo {
+        format,
+        width: image.width(),
+        height: image.height(),
+    })
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageFormat::Jpeg => write!(f, "jpeg"),
+            ImageFormat::Png => write!(f, "png"),
+            ImageFormat::Webp => write!(f, "webp"),
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "jpeg" | "jpg" => Ok(ImageFormat::Jpeg),
+            "png" => Ok(ImageFormat::Png),
+            "webp" => Ok(ImageFormat::Webp),
+            _ => Err(format!(
+                "unknown image format `{s}`, expected jpeg, png or webp"
+            )),
+        }
+    }
+}
+
+/// Offline post-processing of downloaded covers. Any processing decodes and re-encodes the
+/// image, which drops EXIF and other metadata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Processing {
+    /// Scale images down so that neither side is larger than this, keeping the aspect ratio
+    pub max_size: Option<u32>,
+    /// Re-encode in this format instead of the one the image came in
+    pub format: Option<ImageFormat>,
+    /// JPEG quality from 1 to 100
+    pub quality: u8,
+    /// Re-encode even if nothing else needs doing, to get rid of metadata
+    pub strip_metadata: bool,
+}
+
+impl Default for Processing {
+    fn default() -> Self {
+        Self {
+            max_size: None,
+            format: None,
+            quality: 90,
+            strip_metadata: false,
+        }
+    }
+}
+
+impl Processing {
+    /// Whether `apply` would return the image unchanged
+    pub fn is_noop(&self) -> bool {
+        self.max_size.is_none() && self.format.is_none() && !self.strip_metadata
+    }
+
+    /// Returns the processed image, or `image_data` itself if there is nothing to do
+    pub fn apply(&self, image_data: &[u8]) -> Result<Vec<u8>> {
+        if self.is_noop() {
+            return Ok(image_data.to_vec());
+        }
+
+        let source_format = image::guess_format(image_data)
+            .ok()
+            .and_then(ImageFormat::from_image_format)
+            .ok_or(Error::UnsupportedImage)?;
+        let mut image = image::load_from_memory(image_data)?;
+        if let Some(max_size) = self.max_size {
+            if image.width() > max_size || image.height() > max_size {
+                image = image.resize(max_size, max_size, ResizeFilter::Lanczos3);
+            }
+        }
+
+        let mut processed = Vec::new();
+        match self.format.unwrap_or(source_format) {
+            ImageFormat::Jpeg => {
+                // JPEG has no alpha channel
+                let image = DynamicImage::ImageRgb8(image.to_rgb8());
+                image.write_with_encoder(JpegEncoder::new_with_quality(
+                    &mut processed,
+                    self.quality.clamp(1, 100),
+                ))?
+            }
+            ImageFormat::Png => image.write_with_encoder(PngEncoder::new_with_quality(
+                &mut processed,
+                CompressionType::Best,
+                FilterType::Adaptive,
+            ))?,
+            ImageFormat::Webp => {
+                // The WebP encoder only takes 8 bit RGB(A)
+                let image = DynamicImage::ImageRgba8(image.to_rgba8());
+                image.write_with_encoder(WebPEncoder::new_lossless(&mut processed))?
+            }
+        }
+        Ok(processed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::{ImageBuffer, Rgb};
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = ImageBuffer::from_fn(width, height, |x, y| Rgb([x as u8, y as u8, 128]));
+        let mut data = Vec::new();
+        DynamicImage::ImageRgb8(image)
+            .write_with_encoder(PngEncoder::new(&mut data))
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn noop_keeps_bytes() {
+        let data = b"not even an image".to_vec();
+        assert_eq!(Processing::default().apply(&data).unwrap(), data);
+    }
+
+    #[test]
+    fn resizes_keeping_aspect_ratio() {
+        let processing = Processing {
+            max_size: Some(50),
+            ..Default::default()
+        };
+        let processed = processing.apply(&png(200, 100)).unwrap();
+
+        assert_eq!(
+            image::guess_format(&processed).unwrap(),
+            image::ImageFormat::Png
+        );
+        let image = image::load_from_memory(&processed).unwrap();
+        assert_eq!((image.width(), image.height()), (50, 25));
+
+        let small = processing.apply(&png(20, 10)).unwrap();
+        let image = image::load_from_memory(&small).unwrap();
+        assert_eq!((image.width(), image.height()), (20, 10));
+    }
+
+    #[test]
+    fn converts_formats() {
+        for (format, expected) in [
+            (ImageFormat::Jpeg, image::ImageFormat::Jpeg),
+            (ImageFormat::Png, image::ImageFormat::Png),
+            (ImageFormat::Webp, image::ImageFormat::WebP),
+        ] {
+            let processing = Processing {
+                format: Some(format),
+                ..Default::default()
+            };
+            let processed = processing.apply(&png(16, 16)).unwrap();
+            assert_eq!(image::guess_format(&processed).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn strips_exif() {
+        let processing = Processing {
+            format: Some(ImageFormat::Jpeg),
+            ..Default::default()
+        };
+        let jpeg = processing.apply(&png(16, 16)).unwrap();
+        // Insert an APP1 segment with EXIF data right after the start of image marker
+        let exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\0";
+        let mut with_exif = jpeg[..2].to_vec();
+        with_exif.extend_from_slice(&[0xff, 0xe1, 0, exif.len() as u8 + 2]);
+        with_exif.extend_from_slice(exif);
+        with_exif.extend_from_slice(&jpeg[2..]);
+
+        let stripped = Processing {
+            strip_metadata: true,
+            ..Default::default()
+        }
+        .apply(&with_exif)
+        .unwrap();
+        assert!(!stripped.windows(4).any(|window| window == b"Exif"));
+        assert_eq!(
+            image::guess_format(&stripped).unwrap(),
+            image::ImageFormat::Jpeg
+        );
+    }
+
+    #[test]
+    fn unknown_data_is_unsupported() {
+        let processing = Processing {
+            strip_metadata: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            processing.apply(b"<html>Not found</html>"),
+            Err(Error::UnsupportedImage)
+        ));
+    }
+
+    #[test]
+    fn inspects_images() {
+        let info = inspect_image(&png(30, 20)).unwrap();
+        assert_eq!(
+            info,
+            ImageInfo {
+                format: ImageFormat::Png,
+                width: 30,
+                height: 20,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_images() {
+        assert!(matches!(
+            inspect_image(b"<html>Not found</html>"),
+            Err(Error::InvalidImage(_))
+        ));
+        assert!(matches!(inspect_image(b""), Err(Error::InvalidImage(_))));
+
+        let png = png(30, 20);
+        assert!(matches!(
+            inspect_image(&png[..png.len() / 2]),
+            Err(Error::InvalidImage(_))
+        ));
+        let jpeg = Processing {
+            format: Some(ImageFormat::Jpeg),
+            ..Default::default()
+        }
+        .apply(&png)
+        .unwrap();
+        assert!(inspect_image(&jpeg).is_ok());
+        assert!(matches!(
+            inspect_image(&jpeg[..jpeg.len() - 40]),
+            Err(Error::InvalidImage(_))
+        ));
+    }
+
+    #[test]
+    fn parses_formats() {
+        assert_eq!("jpg".parse(), Ok(ImageFormat::Jpeg));
+        assert_eq!("webp".parse(), Ok(ImageFormat::Webp));
+        assert!("gif".parse::<ImageFormat>().is_err());
+    }
+}
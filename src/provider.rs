@@ -0,0 +1,667 @@
+use crate::cache::{LookupCache, LookupKey};
+use crate::error::{Error, Result};
+use crate::process::DownloadedImage;
+use crate::query::{album_queries, track_queries, SearchQuery};
+use crate::size::{CoverImage, SizePolicy};
+use crate::tags::{AlbumInfo, TrackInfo};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A track or album found by a provider
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchResult {
+    /// The provider's ID of the track, or of the album when an album was searched for
+    pub id: Option<String>,
+    /// `None` when an album was searched for or looked up by its ID
+    pub track: Option<String>,
+    pub album: String,
+    pub artists: Vec<String>,
+    /// Every size the cover is available in, with absolute URLs
+    pub images: Vec<CoverImage>,
+}
+
+/// The results of the first query of a search that found anything
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchResults {
+    /// The query as sent to the provider
+    pub query: String,
+    pub results: Vec<SearchResult>,
+}
+
+impl SearchResults {
+    /// Keeps the results that have an image matching `size_policy`, returns `None` if none has
+    pub fn usable(
+        query: impl fmt::Display,
+        results: Vec<SearchResult>,
+        size_policy: &SizePolicy,
+    ) -> Option<Self> {
+        let results: Vec<_> = results
+            .into_iter()
+            .filter(|result| size_policy.select(&result.images).is_some())
+            .collect();
+        if results.is_empty() {
+            log::debug!("No results for `{query}`");
+            return None;
+        }
+        Some(Self {
+            query: query.to_string(),
+            results,
+        })
+    }
+}
+
+/// The search result chosen for a lookup and the cover that belongs to it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoverMatch {
+    /// The provider's ID of the track, or of the album when an album was searched for
+    pub id: Option<String>,
+    /// `None` when an album was searched for
+    pub track: Option<String>,
+    pub album: String,
+    pub artists: Vec<String>,
+    /// The image chosen by the client's size policy
+    pub image: CoverImage,
+    /// Every size the cover is available in
+    pub images: Vec<CoverImage>,
+    /// The search query that found the match
+    #[serde(default)]
+    pub query: String,
+    /// Every usable result of that query, best first
+    #[serde(default)]
+    pub candidates: Vec<Candidate>,
+    /// The name of the provider that found the match, see `CoverProvider::name`
+    pub provider: String,
+    /// The score of the match among the `candidates`
+    pub score: usize,
+}
+
+impl fmt::Display for CoverMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.track {
+            Some(track) => write!(
+                f,
+                "{track} by {} from {}",
+                self.artists.join(", "),
+                self.album
+            ),
+            None => write!(f, "{} by {}", self.album, self.artists.join(", ")),
+        }
+    }
+}
+
+/// A search result that was considered for a lookup
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Candidate {
+    pub id: Option<String>,
+    /// `None` when an album was searched for
+    pub track: Option<String>,
+    pub album: String,
+    pub artists: Vec<String>,
+    /// The sum of the edit distances to the tags, lower is better
+    pub score: usize,
+}
+
+/// A service that can be searched for tracks and albums and serves their covers
+#[async_trait]
+pub trait CoverProvider: Send + Sync {
+    /// The name used with --providers and in reports
+    fn name(&self) -> &'static str;
+
+    /// Runs one query of `search_tracks` or `search_albums` and returns its usable results, see
+    /// `SearchResults::usable`. `album_name` is the album looked up, whose results may be
+    /// checked first.
+    async fn search_results(
+        &self,
+        query: &SearchQuery,
+        album_name: &str,
+        size_policy: &SizePolicy,
+    ) -> Result<Option<SearchResults>>;
+
+    /// Searches with increasingly broad queries (see `track_queries`) and returns the usable
+    /// results of the first one that has any
+    async fn search_tracks(
+        &self,
+        track_name: &str,
+        artist_names: &[&str],
+        album_name: &str,
+        size_policy: &SizePolicy,
+    ) -> Result<Option<SearchResults>> {
+        let queries = track_queries(track_name, artist_names, album_name);
+        first_usable(self, queries, album_name, size_policy).await
+    }
+
+    /// Like `search_tracks`, but searches for albums (see `album_queries`)
+    async fn search_albums(
+        &self,
+        album_name: &str,
+        artist_name: &str,
+        size_policy: &SizePolicy,
+    ) -> Result<Option<SearchResults>> {
+        let queries = album_queries(album_name, artist_name);
+        first_usable(self, queries, album_name, size_policy).await
+    }
+
+    /// Looks up the release with the MusicBrainz ID `release_id`. Providers that don't know
+    /// these IDs return `None`, and so do the others if the release has no usable cover, which
+    /// makes the lookup fall back to searching.
+    async fn find_release(
+        &self,
+        _release_id: &str,
+        _size_policy: &SizePolicy,
+    ) -> Result<Option<SearchResults>> {
+        Ok(None)
+    }
+
+    /// Downloads an image and makes sure that it is one, see `inspect_image`
+    async fn download_image(&self, image_url: &str) -> Result<DownloadedImage>;
+}
+
+/// Runs `queries` in order and returns the usable results of the first one that has any
+async fn first_usable<P: CoverProvider + ?Sized>(
+    provider: &P,
+    queries: Vec<SearchQuery>,
+    album_name: &str,
+    size_policy: &SizePolicy,
+) -> Result<Option<SearchResults>> {
+    for query in queries {
+        if let Some(found) = provider
+            .search_results(&query, album_name, size_policy)
+            .await?
+        {
+            return Ok(Some(found));
+        }
+    }
+    Ok(None)
+}
+
+/// The tags a cover is looked up by
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Lookup<'a> {
+    Track {
+        track_name: &'a str,
+        artist_names: &'a [&'a str],
+        album_name: &'a str,
+        release_id: Option<&'a str>,
+    },
+    Album {
+        album_name: &'a str,
+        artist_name: &'a str,
+        release_id: Option<&'a str>,
+    },
+}
+
+impl Lookup<'_> {
+    /// Looks up the release by its ID if there is one, otherwise searches `provider` and builds
+    /// the match for the best result, see `best_match`
+    pub(crate) async fn find(
+        self,
+        provider: &dyn CoverProvider,
+        size_policy: &SizePolicy,
+    ) -> Result<CoverMatch> {
+        if let Some(release_id) = self.release_id() {
+            if let Some(found) = provider.find_release(release_id, size_policy).await? {
+                // The ID names the release, so there is nothing to rank
+                let results = found.results.into_iter().map(|result| (0, result));
+                return cover_match(
+                    provider.name(),
+                    found.query,
+                    results.collect(),
+                    0,
+                    size_policy,
+                );
+            }
+        }
+        let found = match self {
+            Lookup::Track {
+                track_name,
+                artist_names,
+                album_name,
+                ..
+            } => {
+                provider
+                    .search_tracks(track_name, artist_names, album_name, size_policy)
+                    .await?
+            }
+            Lookup::Album {
+                album_name,
+                artist_name,
+                ..
+            } => {
+                provider
+                    .search_albums(album_name, artist_name, size_policy)
+                    .await?
+            }
+        };
+        let found = found.ok_or_else(|| Error::NoMatch(self.to_string()))?;
+        self.best_match(provider.name(), found, size_policy)
+    }
+
+    /// Ranks the results by the edit distance of their tags to the ones looked up. A result from
+    /// exactly the album looked up is chosen over better ranked ones.
+    fn best_match(
+        self,
+        provider: &str,
+        found: SearchResults,
+        size_policy: &SizePolicy,
+    ) -> Result<CoverMatch> {
+        let mut results: Vec<_> = found
+            .results
+            .into_iter()
+            .map(|result| (self.score(&result), result))
+            .collect();
+        results.sort_by_key(|(score, _)| *score);
+
+        let chosen = results
+            .iter()
+            .position(|(_, result)| result.album == self.album_name())
+            .unwrap_or(0);
+        cover_match(provider, found.query, results, chosen, size_policy)
+    }
+
+    /// The sum of the edit distances of the tags of `result` to the ones looked up
+    fn score(&self, result: &SearchResult) -> usize {
+        let artists: Vec<_> = result.artists.iter().map(String::as_str).collect();
+        match *self {
+            Lookup::Track {
+                track_name,
+                artist_names,
+                album_name,
+                ..
+            } => {
+                let track = result.track.as_deref().unwrap_or_default();
+                edit_distance::edit_distance(track_name, track)
+                    + calculate_average_artist_names_distance(artist_names, &artists)
+                    + edit_distance::edit_distance(album_name, &result.album)
+            }
+            Lookup::Album {
+                album_name,
+                artist_name,
+                ..
+            } => {
+                edit_distance::edit_distance(album_name, &result.album)
+                    + calculate_average_artist_names_distance(&[artist_name], &artists)
+            }
+        }
+    }
+
+    /// Whether `cover` is close enough to be used without asking other providers: its score is
+    /// at most a quarter of the length of the tags looked up
+    fn is_confident(&self, cover: &CoverMatch) -> bool {
+        let length: usize = match *self {
+            Lookup::Track {
+                track_name,
+                artist_names,
+                album_name,
+                ..
+            } => [track_name, album_name]
+                .iter()
+                .chain(artist_names)
+                .map(|tag| tag.chars().count())
+                .sum(),
+            Lookup::Album {
+                album_name,
+                artist_name,
+                ..
+            } => album_name.chars().count() + artist_name.chars().count(),
+        };
+        cover.score * 4 <= length
+    }
+
+    fn album_name(&self) -> &str {
+        match *self {
+            Lookup::Track { album_name, .. } | Lookup::Album { album_name, .. } => album_name,
+        }
+    }
+
+    fn release_id(&self) -> Option<&str> {
+        match *self {
+            Lookup::Track { release_id, .. } | Lookup::Album { release_id, .. } => release_id,
+        }
+    }
+
+    fn key(&self, size_policy: &SizePolicy) -> LookupKey {
+        let key = match *self {
+            Lookup::Track {
+                track_name,
+                artist_names,
+                album_name,
+                ..
+            } => LookupKey::track(track_name, artist_names, album_name),
+            Lookup::Album {
+                album_name,
+                artist_name,
+                ..
+            } => LookupKey::album(album_name, artist_name),
+        };
+        key.with_release_id(self.release_id())
+            .with_size_policy(size_policy)
+    }
+}
+
+impl fmt::Display for Lookup<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Lookup::Track {
+                track_name,
+                artist_names,
+                ..
+            } => write!(f, "{track_name} by {}", artist_names.join(", ")),
+            Lookup::Album {
+                album_name,
+                artist_name,
+                ..
+            } => write!(f, "{album_name} by {artist_name}"),
+        }
+    }
+}
+
+/// Looks up covers with several providers in order, until one of them has a confident match
+pub struct CoverFinder {
+    providers: Vec<Box<dyn CoverProvider>>,
+    size_policy: SizePolicy,
+    lookup_cache: Option<LookupCache>,
+}
+
+impl CoverFinder {
+    pub fn new(providers: Vec<Box<dyn CoverProvider>>) -> Self {
+        Self {
+            providers,
+            size_policy: SizePolicy::default(),
+            lookup_cache: None,
+        }
+    }
+
+    /// Pick the size of each cover according to `size_policy`. Results that have no image
+    /// matching the policy are skipped.
+    pub fn with_size_policy(mut self, size_policy: SizePolicy) -> Self {
+        self.size_policy = size_policy;
+        self
+    }
+
+    /// Reuse the outcome of earlier lookups stored in `lookup_cache` instead of searching again
+    pub fn with_lookup_cache(mut self, lookup_cache: LookupCache) -> Self {
+        self.lookup_cache = Some(lookup_cache);
+        self
+    }
+
+    pub async fn find_track_cover(&self, track: &TrackInfo) -> Result<CoverMatch> {
+        self.find(Lookup::Track {
+            track_name: &track.title,
+            artist_names: &track.artist_names(),
+            album_name: &track.album,
+            release_id: track.musicbrainz_release_id.as_deref(),
+        })
+        .await
+    }
+
+    pub async fn find_album_cover(&self, album: &AlbumInfo) -> Result<CoverMatch> {
+        self.find(Lookup::Album {
+            album_name: &album.album,
+            artist_name: &album.album_artist,
+            release_id: album.musicbrainz_release_id.as_deref(),
+        })
+        .await
+    }
+
+    /// Downloads an image of a cover through the provider that found it
+    pub async fn download_image(
+        &self,
+        cover: &CoverMatch,
+        image_url: &str,
+    ) -> Result<DownloadedImage> {
+        let provider = self
+            .providers
+            .iter()
+            .find(|provider| provider.name() == cover.provider)
+            .ok_or(Error::InvalidResponse(format!(
+                "the match is from `{}`, which is not one of the providers",
+                cover.provider
+            )))?;
+        provider.download_image(image_url).await
+    }
+
+    /// Asks the providers in order. Without a confident match, the best match of any of them is
+    /// used, and without any match the first error other than `NoMatch`.
+    async fn find(&self, lookup: Lookup<'_>) -> Result<CoverMatch> {
+        let mut best: Option<CoverMatch> = None;
+        let mut error = None;
+        for (i, provider) in self.providers.iter().enumerate() {
+            let is_last = i + 1 == self.providers.len();
+            match self.find_with(provider.as_ref(), lookup).await {
+                Ok(cover) if lookup.is_confident(&cover) => return Ok(cover),
+                Ok(cover) => {
+                    if !is_last {
+                        log::info!(
+                            "Not confident about {cover} from {}, trying the next provider",
+                            provider.name()
+                        );
+                    }
+                    if best.as_ref().is_none_or(|best| cover.score < best.score) {
+                        best = Some(cover);
+                    }
+                }
+                Err(Error::NoMatch(_)) => {}
+                Err(err) => {
+                    if !is_last {
+                        log::warn!("Search on {} failed: {err}", provider.name());
+                    }
+                    error.get_or_insert(err);
+                }
+            }
+        }
+        match (best, error) {
+            (Some(cover), _) => Ok(cover),
+            (None, Some(err)) => Err(err),
+            (None, None) => Err(Error::NoMatch(lookup.to_string())),
+        }
+    }
+
+    /// Looks up a cover with one provider, through the lookup cache if there is one
+    async fn find_with(
+        &self,
+        provider: &dyn CoverProvider,
+        lookup: Lookup<'_>,
+    ) -> Result<CoverMatch> {
+        let key = lookup.key(&self.size_policy);
+        if let Some(cached) = self.cached(provider.name(), &key, lookup) {
+            return cached;
+        }
+        let found = lookup.find(provider, &self.size_policy).await;
+        self.store(provider.name(), key, &found);
+        found
+    }
+
+    /// The outcome of an earlier lookup, unless it has no image that matches the size policy
+    fn cached(
+        &self,
+        provider: &str,
+        key: &LookupKey,
+        lookup: Lookup,
+    ) -> Option<Result<CoverMatch>> {
+        let Some(mut cover) = self.lookup_cache.as_ref()?.get(provider, key)? else {
+            log::info!("Using cached result: no match for {lookup}");
+            return Some(Err(Error::NoMatch(lookup.to_string())));
+        };
+        cover.image = self.size_policy.select(&cover.images)?.clone();
+        log::info!("Using cached result: {cover}");
+        Some(Ok(cover))
+    }
+
+    /// Stores matches and searches without a match in the lookup cache, but not other failures
+    fn store(&self, provider: &str, key: LookupKey, found: &Result<CoverMatch>) {
+        let Some(lookup_cache) = &self.lookup_cache else {
+            return;
+        };
+        match found {
+            Ok(cover) => lookup_cache.insert(provider, key, Some(cover.clone())),
+            Err(Error::NoMatch(_)) => lookup_cache.insert(provider, key, None),
+            Err(_) => {}
+        }
+    }
+}
+
+/// Builds the match for the `chosen` one of the ranked `results`
+fn cover_match(
+    provider: &str,
+    query: String,
+    mut results: Vec<(usize, SearchResult)>,
+    chosen: usize,
+    size_policy: &SizePolicy,
+) -> Result<CoverMatch> {
+    let candidates = results
+        .iter()
+        .map(|(score, result)| Candidate {
+            id: result.id.clone(),
+            track: result.track.clone(),
+            album: result.album.clone(),
+            artists: result.artists.clone(),
+            score: *score,
+        })
+        .collect();
+    let (score, result) = results.swap_remove(chosen);
+    let image = size_policy
+        .select(&result.images)
+        .cloned()
+        .ok_or(Error::InvalidResponse(
+            "no image matches the size policy".to_string(),
+        ))?;
+
+    Ok(CoverMatch {
+        id: result.id,
+        track: result.track,
+        album: result.album,
+        artists: result.artists,
+        image,
+        images: result.images,
+        query,
+        candidates,
+        provider: provider.to_string(),
+        score,
+    })
+}
+
+fn calculate_average_artist_names_distance(a: &[&str], b: &[&str]) -> usize {
+    if a.is_empty() || b.is_empty() {
+        return a.iter().chain(b).map(|name| name.chars().count()).sum();
+    }
+
+    let num_artists = a.len();
+    let num_found_artists = b.len();
+
+    let (larger, smaller) = if num_artists > num_found_artists {
+        (a, b)
+    } else {
+        (b, a)
+    };
+
+    let mut total_distance = 0usize;
+    for outer_artist_name in smaller.iter() {
+        let mut min_distance: Option<usize> = None;
+        for inner_artist_name in larger.iter() {
+            let distance = edit_distance::edit_distance(outer_artist_name, inner_artist_name);
+            min_distance = match min_distance {
+                Some(min_distance) => Some(min_distance.min(distance)),
+                None => Some(distance),
+            };
+        }
+        total_distance += min_distance.expect("There should be at least one artist for the track");
+    }
+
+    total_distance / num_found_artists
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::query::SearchType;
+
+    /// Answers every album search with the same album and finds no tracks
+    struct FixedProvider {
+        name: &'static str,
+        album: Option<&'static str>,
+    }
+
+    #[async_trait]
+    impl CoverProvider for FixedProvider {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        async fn search_results(
+            &self,
+            query: &SearchQuery,
+            _album_name: &str,
+            size_policy: &SizePolicy,
+        ) -> Result<Option<SearchResults>> {
+            if query.search_type() == SearchType::Track {
+                return Ok(None);
+            }
+            let Some(album) = self.album else {
+                return Err(Error::InvalidResponse("unavailable".to_string()));
+            };
+            let result = SearchResult {
+                id: None,
+                track: None,
+                album: album.to_string(),
+                artists: vec!["Queen".to_string()],
+                images: vec![CoverImage {
+                    url: format!("https://{}/cover.jpg", self.name),
+                    width: Some(640),
+                    height: Some(640),
+                }],
+            };
+            Ok(SearchResults::usable(album, vec![result], size_policy))
+        }
+
+        async fn download_image(&self, _image_url: &str) -> Result<DownloadedImage> {
+            Err(Error::InvalidResponse("no images".to_string()))
+        }
+    }
+
+    fn finder(providers: &[(&'static str, Option<&'static str>)]) -> CoverFinder {
+        CoverFinder::new(
+            providers
+                .iter()
+                .map(|&(name, album)| {
+                    Box::new(FixedProvider { name, album }) as Box<dyn CoverProvider>
+                })
+                .collect(),
+        )
+    }
+
+    fn album() -> AlbumInfo {
+        AlbumInfo {
+            album: "A Night at the Opera".to_string(),
+            album_artist: "Queen".to_string(),
+            musicbrainz_release_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_until_a_confident_match() {
+        let cover = finder(&[
+            ("first", Some("Greatest Hits")),
+            ("second", None),
+            ("third", Some("A Night at the Opera")),
+            ("fourth", Some("A Night at the Opera")),
+        ])
+        .find_album_cover(&album())
+        .await
+        .unwrap();
+        assert_eq!(cover.provider, "third");
+        assert_eq!(cover.score, 0);
+
+        let cover = finder(&[("first", Some("A Day at the Races")), ("second", None)])
+            .find_album_cover(&album())
+            .await
+            .unwrap();
+        assert_eq!(cover.provider, "first");
+
+        let err = finder(&[("first", None)])
+            .find_album_cover(&album())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidResponse(_)));
+    }
+}
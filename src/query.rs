@@ -0,0 +1,207 @@
+use std::fmt;
+
+/// What kind of item a search returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchType {
+    Track,
+    Album,
+}
+
+impl SearchType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SearchType::Track => "track",
+            SearchType::Album => "album",
+        }
+    }
+}
+
+/// A field filter in a search query, e.g. the `artist` in `artist:Queen`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Track,
+    Artist,
+    Album,
+}
+
+impl Field {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Field::Track => "track",
+            Field::Artist => "artist",
+            Field::Album => "album",
+        }
+    }
+}
+
+/// A Spotify search query built from field filters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchQuery {
+    search_type: SearchType,
+    filters: Vec<(Field, String)>,
+}
+
+impl SearchQuery {
+    pub fn new(search_type: SearchType) -> Self {
+        Self {
+            search_type,
+            filters: Vec::new(),
+        }
+    }
+
+    /// Adds `field:value` to the query, unless `value` is blank
+    pub fn filter(mut self, field: Field, value: &str) -> Self {
+        let value = value.trim();
+        if !value.is_empty() {
+            self.filters.push((field, value.to_string()));
+        }
+        self
+    }
+
+    /// Adds one `field:value` filter per value
+    pub fn filters(self, field: Field, values: &[&str]) -> Self {
+        values
+            .iter()
+            .fold(self, |query, value| query.filter(field, value))
+    }
+
+    /// The filters in the order they were added
+    pub fn fields(&self) -> impl Iterator<Item = (Field, &str)> {
+        self.filters
+            .iter()
+            .map(|(field, value)| (*field, value.as_str()))
+    }
+
+    pub fn search_type(&self) -> SearchType {
+        self.search_type
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// The URL query string for the search endpoint, with every value encoded
+    pub fn to_query_string(&self) -> String {
+        format!(
+            "q={}&type={}",
+            urlencoding::encode(&self.to_string()),
+            self.search_type.as_str()
+        )
+    }
+}
+
+impl fmt::Display for SearchQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let filters: Vec<_> = self
+            .filters
+            .iter()
+            .map(|(field, value)| format!("{}:{value}", field.as_str()))
+            .collect();
+        write!(f, "{}", filters.join(" "))
+    }
+}
+
+/// Queries to try for a track, from the narrowest to the broadest
+pub fn track_queries(
+    track_name: &str,
+    artist_names: &[&str],
+    album_name: &str,
+) -> Vec<SearchQuery> {
+    let query = || SearchQuery::new(SearchType::Track);
+    let queries = vec![
+        query()
+            .filter(Field::Track, track_name)
+            .filters(Field::Artist, artist_names),
+        query()
+            .filter(Field::Track, track_name)
+            .filter(Field::Album, album_name),
+        query()
+            .filter(Field::Album, album_name)
+            .filters(Field::Artist, artist_names),
+        query().filter(Field::Track, track_name),
+    ];
+    dedup(queries)
+}
+
+/// Queries to try for an album, from the narrowest to the broadest
+pub fn album_queries(album_name: &str, artist_name: &str) -> Vec<SearchQuery> {
+    let query = || SearchQuery::new(SearchType::Album);
+    let queries = vec![
+        query()
+            .filter(Field::Album, album_name)
+            .filter(Field::Artist, artist_name),
+        query().filter(Field::Album, album_name),
+    ];
+    dedup(queries)
+}
+
+/// Drops empty queries and ones that are the same as an earlier query because of missing fields
+fn dedup(queries: Vec<SearchQuery>) -> Vec<SearchQuery> {
+    let mut unique: Vec<SearchQuery> = Vec::new();
+    for query in queries {
+        if !query.is_empty() && !unique.contains(&query) {
+            unique.push(query);
+        }
+    }
+    unique
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn all_artists_are_encoded() {
+        let query = SearchQuery::new(SearchType::Track)
+            .filter(Field::Track, "Under Pressure")
+            .filters(Field::Artist, &["Queen", "David Bowie"]);
+        assert_eq!(
+            query.to_query_string(),
+            "q=track%3AUnder%20Pressure%20artist%3AQueen%20artist%3ADavid%20Bowie&type=track"
+        );
+    }
+
+    #[test]
+    fn special_characters_are_encoded() {
+        let query = SearchQuery::new(SearchType::Album)
+            .filter(Field::Artist, "Simon & Garfunkel")
+            .filter(Field::Album, "#1 Record")
+            .filter(Field::Album, "Björk");
+        assert_eq!(
+            query.to_query_string(),
+            "q=artist%3ASimon%20%26%20Garfunkel%20album%3A%231%20Record%20album%3ABj%C3%B6rk&type=album"
+        );
+    }
+
+    #[test]
+    fn blank_values_are_skipped() {
+        let query = SearchQuery::new(SearchType::Track)
+            .filter(Field::Track, "Bohemian Rhapsody")
+            .filters(Field::Artist, &["", "  "]);
+        assert_eq!(query.to_string(), "track:Bohemian Rhapsody");
+    }
+
+    #[test]
+    fn track_queries_get_broader() {
+        let queries: Vec<_> =
+            track_queries("Bohemian Rhapsody", &["Queen"], "A Night at the Opera")
+                .iter()
+                .map(|query| query.to_string())
+                .collect();
+        assert_eq!(
+            queries,
+            [
+                "track:Bohemian Rhapsody artist:Queen",
+                "track:Bohemian Rhapsody album:A Night at the Opera",
+                "album:A Night at the Opera artist:Queen",
+                "track:Bohemian Rhapsody",
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_queries_are_dropped() {
+        let queries = track_queries("Bohemian Rhapsody", &[], "");
+        assert_eq!(queries.len(), 1);
+    }
+}
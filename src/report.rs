@@ -0,0 +1,422 @@
+use crate::error::{Error, Result};
+use crate::process::ImageInfo;
+use crate::provider::{Candidate, CoverMatch};
+use crate::size::CoverImage;
+use crate::tags::{AlbumInfo, TrackInfo};
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// How a `Report` is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// One JSON array, written once the run is done
+    Json,
+    /// One JSON object per line, written as the run goes
+    Ndjson,
+    /// One row per destination, written as the run goes
+    Csv,
+}
+
+/// How the lookup for a file or directory ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Found,
+    NoMatch,
+    /// Another lookup already provided the cover and there was nothing to embed into
+    Skipped,
+    Error,
+}
+
+/// The tags a lookup was made with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReportTags {
+    /// `None` for album lookups
+    pub title: Option<String>,
+    pub artists: Vec<String>,
+    pub album: String,
+    pub album_artist: Option<String>,
+}
+
+impl From<&TrackInfo> for ReportTags {
+    fn from(track: &TrackInfo) -> Self {
+        Self {
+            title: Some(track.title.clone()),
+            artists: track.artists.clone(),
+            album: track.album.clone(),
+            album_artist: track.album_artist.clone(),
+        }
+    }
+}
+
+impl From<&AlbumInfo> for ReportTags {
+    fn from(album: &AlbumInfo) -> Self {
+        Self {
+            title: None,
+            artists: vec![album.album_artist.clone()],
+            album: album.album.clone(),
+            album_artist: Some(album.album_artist.clone()),
+        }
+    }
+}
+
+/// The match that was chosen, without the details that are reported separately
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReportMatch {
+    pub provider: String,
+    pub id: Option<String>,
+    pub track: Option<String>,
+    pub album: String,
+    pub artists: Vec<String>,
+}
+
+/// A file that was (or with --dry-run would be) written or embedded into
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Destination {
+    pub path: PathBuf,
+    /// Whether the cover is embedded into the tags of the file at `path`
+    pub embedded: bool,
+    pub url: String,
+    /// The size of the image that was written, or with --dry-run the one the provider claims
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl Destination {
+    pub fn new(path: impl Into<PathBuf>, embedded: bool, image: &CoverImage) -> Self {
+        Self {
+            path: path.into(),
+            embedded,
+            url: image.url.clone(),
+            width: image.width,
+            height: image.height,
+        }
+    }
+
+    /// Report the size of the image that was actually written
+    pub fn with_size(mut self, info: &ImageInfo) -> Self {
+        self.width = Some(info.width);
+        self.height = Some(info.height);
+        self
+    }
+}
+
+/// Everything about the lookup for one audio file, or one directory with --album
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReportEntry {
+    pub item: PathBuf,
+    pub tags: ReportTags,
+    pub status: Status,
+    pub error: Option<String>,
+    pub query: Option<String>,
+    pub candidates: Vec<Candidate>,
+    #[serde(rename = "match")]
+    pub cover: Option<ReportMatch>,
+    pub destinations: Vec<Destination>,
+}
+
+impl ReportEntry {
+    pub fn new(item: impl Into<PathBuf>, tags: ReportTags) -> Self {
+        Self {
+            item: item.into(),
+            tags,
+            status: Status::Skipped,
+            error: None,
+            query: None,
+            candidates: Vec::new(),
+            cover: None,
+            destinations: Vec::new(),
+        }
+    }
+
+    pub fn found(&mut self, cover: &CoverMatch) {
+        self.status = Status::Found;
+        self.query = Some(cover.query.clone());
+        self.candidates = cover.candidates.clone();
+        self.cover = Some(ReportMatch {
+            provider: cover.provider.clone(),
+            id: cover.id.clone(),
+            track: cover.track.clone(),
+            album: cover.album.clone(),
+            artists: cover.artists.clone(),
+        });
+    }
+
+    pub fn failed(&mut self, err: &impl fmt::Display) {
+        self.status = Status::Error;
+        self.error = Some(err.to_string());
+    }
+
+    /// Records a failed search, which is `NoMatch` rather than `Error` if nothing matched
+    pub fn search_failed(&mut self, err: &Error) {
+        self.failed(err);
+        if matches!(err, Error::NoMatch(_)) {
+            self.status = Status::NoMatch;
+        }
+    }
+}
+
+/// Structured results of a run, for other programs to consume
+pub struct Report {
+    format: ReportFormat,
+    writer: Mutex<ReportWriter>,
+}
+
+struct ReportWriter {
+    file: BufWriter<fs::File>,
+    /// Entries collected for `ReportFormat::Json`
+    entries: Vec<ReportEntry>,
+}
+
+const CSV_HEADER: [&str; 18] = [
+    "item",
+    "status",
+    "error",
+    "title",
+    "artists",
+    "album",
+    "album_artist",
+    "query",
+    "candidates",
+    "match_provider",
+    "match_id",
+    "match_track",
+    "match_album",
+    "match_artists",
+    "destination",
+    "embedded",
+    "url",
+    "size",
+];
+
+impl Report {
+    /// Creates (or truncates) the report file
+    pub fn create(path: impl AsRef<Path>, format: ReportFormat) -> Result<Self> {
+        let mut file = BufWriter::new(fs::File::create(path)?);
+        if format == ReportFormat::Csv {
+            writeln!(file, "{}", CSV_HEADER.join(","))?;
+            file.flush()?;
+        }
+        Ok(Self {
+            format,
+            writer: Mutex::new(ReportWriter {
+                file,
+                entries: Vec::new(),
+            }),
+        })
+    }
+
+    pub fn add(&self, entry: ReportEntry) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        match self.format {
+            ReportFormat::Json => writer.entries.push(entry),
+            ReportFormat::Ndjson => {
+                serde_json::to_writer(&mut writer.file, &entry)?;
+                writeln!(writer.file)?;
+            }
+            ReportFormat::Csv => {
+                for row in csv_rows(&entry) {
+                    writeln!(writer.file, "{}", row.join(","))?;
+                }
+            }
+        }
+        // Flush every entry, so that the report is complete up to where a run was interrupted
+        writer.file.flush()?;
+        Ok(())
+    }
+
+    /// Writes what `add` didn't yet. Call this once, after the last entry.
+    pub fn finish(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        if self.format == ReportFormat::Json {
+            let entries = std::mem::take(&mut writer.entries);
+            serde_json::to_writer_pretty(&mut writer.file, &entries)?;
+            writeln!(writer.file)?;
+        }
+        writer.file.flush()?;
+        Ok(())
+    }
+}
+
+/// One row per destination, or a single row without one
+fn csv_rows(entry: &ReportEntry) -> Vec<Vec<String>> {
+    let join = |values: &[String]| values.join("; ");
+    let candidates: Vec<_> = entry
+        .candidates
+        .iter()
+        .map(|candidate| {
+            let name = candidate.track.as_ref().unwrap_or(&candidate.album);
+            format!(
+                "{name} by {} ({})",
+                candidate.artists.join(" & "),
+                candidate.score
+            )
+        })
+        .collect();
+    let cover = entry.cover.as_ref();
+    let columns = [
+        entry.item.display().to_string(),
+        entry.status.to_string(),
+        entry.error.clone().unwrap_or_default(),
+        entry.tags.title.clone().unwrap_or_default(),
+        join(&entry.tags.artists),
+        entry.tags.album.clone(),
+        entry.tags.album_artist.clone().unwrap_or_default(),
+        entry.query.clone().unwrap_or_default(),
+        join(&candidates),
+        cover
+            .map(|cover| cover.provider.clone())
+            .unwrap_or_default(),
+        cover.and_then(|cover| cover.id.clone()).unwrap_or_default(),
+        cover
+            .and_then(|cover| cover.track.clone())
+            .unwrap_or_default(),
+        cover.map(|cover| cover.album.clone()).unwrap_or_default(),
+        cover.map(|cover| join(&cover.artists)).unwrap_or_default(),
+    ];
+
+    let destinations: Vec<_> = entry
+        .destinations
+        .iter()
+        .map(|destination| {
+            let size = match (destination.width, destination.height) {
+                (Some(width), Some(height)) => format!("{width}x{height}"),
+                _ => String::new(),
+            };
+            [
+                destination.path.display().to_string(),
+                destination.embedded.to_string(),
+                destination.url.clone(),
+                size,
+            ]
+        })
+        .collect();
+    let destinations = if destinations.is_empty() {
+        vec![Default::default()]
+    } else {
+        destinations
+    };
+
+    destinations
+        .into_iter()
+        .map(|destination| {
+            columns
+                .iter()
+                .chain(destination.iter())
+                .map(|value| csv_field(value))
+                .collect()
+        })
+        .collect()
+}
+
+/// Quotes `value` if it contains a separator, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Found => write!(f, "found"),
+            Status::NoMatch => write!(f, "no-match"),
+            Status::Skipped => write!(f, "skipped"),
+            Status::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl fmt::Display for ReportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReportFormat::Json => write!(f, "json"),
+            ReportFormat::Ndjson => write!(f, "ndjson"),
+            ReportFormat::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ReportFormat::Json),
+            "ndjson" => Ok(ReportFormat::Ndjson),
+            "csv" => Ok(ReportFormat::Csv),
+            _ => Err(format!(
+                "unknown report format `{s}`, expected json, ndjson or csv"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry() -> ReportEntry {
+        let mut entry = ReportEntry::new(
+            "music/01.mp3",
+            ReportTags {
+                title: Some("Bohemian Rhapsody".to_string()),
+                artists: vec!["Queen".to_string()],
+                album: "A Night at the Opera".to_string(),
+                album_artist: None,
+            },
+        );
+        entry.failed(&"Connection reset, \"twice\"");
+        entry.destinations = vec![
+            Destination::new(
+                "music/cover.jpg",
+                false,
+                &CoverImage {
+                    url: "/images/640.jpg".to_string(),
+                    width: Some(640),
+                    height: Some(640),
+                },
+            ),
+            Destination::new(
+                "music/small.jpg",
+                false,
+                &CoverImage {
+                    url: "/images/64.jpg".to_string(),
+                    width: None,
+                    height: None,
+                },
+            ),
+        ];
+        entry
+    }
+
+    #[test]
+    fn csv_has_a_row_per_destination() {
+        let rows = csv_rows(&entry());
+        assert_eq!(rows.len(), 2);
+        assert!(rows.iter().all(|row| row.len() == CSV_HEADER.len()));
+        assert_eq!(rows[0][1], "error");
+        assert_eq!(rows[0][2], "\"Connection reset, \"\"twice\"\"\"");
+        assert_eq!(rows[0][17], "640x640");
+        assert_eq!(rows[1][14], "music/small.jpg");
+
+        let rows = csv_rows(&ReportEntry::new("music", entry().tags));
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][1], "skipped");
+    }
+
+    #[test]
+    fn json_uses_kebab_case_status() {
+        let json = serde_json::to_value(entry()).unwrap();
+        assert_eq!(json["status"], "error");
+        assert_eq!(json["match"], serde_json::Value::Null);
+        assert_eq!(json["destinations"][0]["width"], 640);
+    }
+}
@@ -0,0 +1,184 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// One of the sizes a cover is available in
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoverImage {
+    pub url: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl CoverImage {
+    /// Reads an entry of an `images` array, returns `None` if it has no URL
+    pub(crate) fn from_json(image: &serde_json::Value) -> Option<Self> {
+        Some(Self {
+            url: image["url"].as_str()?.to_string(),
+            width: image["width"]
+                .as_u64()
+                .and_then(|width| width.try_into().ok()),
+            height: image["height"]
+                .as_u64()
+                .and_then(|height| height.try_into().ok()),
+        })
+    }
+
+    /// The longer side in pixels, if the API reported it
+    pub fn size(&self) -> Option<u32> {
+        self.width.max(self.height)
+    }
+}
+
+impl fmt::Display for CoverImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.width, self.height) {
+            (Some(width), Some(height)) => write!(f, "{} ({width}x{height})", self.url),
+            _ => write!(f, "{}", self.url),
+        }
+    }
+}
+
+/// Which of the available sizes of a cover to use
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SizePolicy {
+    #[default]
+    Largest,
+    Smallest,
+    /// The size closest to this many pixels
+    Closest(u32),
+    /// The smallest size that is at least this many pixels
+    Min(u32),
+    /// The image as it was uploaded, for providers that have it, otherwise the largest size
+    Original,
+}
+
+impl SizePolicy {
+    /// Picks an image according to the policy. Images without dimensions are only used by
+    /// `Original` or when none of them has any, and `Min` returns `None` when no image is large
+    /// enough.
+    pub fn select<'a>(&self, images: &'a [CoverImage]) -> Option<&'a CoverImage> {
+        if *self == SizePolicy::Original {
+            // Providers only leave out the dimensions of originals
+            return images
+                .iter()
+                .find(|image| image.size().is_none())
+                .or_else(|| SizePolicy::Largest.select(images));
+        }
+        let sized = images
+            .iter()
+            .filter_map(|image| Some((image.size()?, image)));
+        if sized.clone().next().is_none() {
+            return match self {
+                SizePolicy::Min(_) => None,
+                _ => images.first(),
+            };
+        }
+
+        let selected = match *self {
+            SizePolicy::Largest | SizePolicy::Original => sized.max_by_key(|(size, _)| *size),
+            SizePolicy::Smallest => sized.min_by_key(|(size, _)| *size),
+            SizePolicy::Closest(target) => sized.min_by_key(|(size, _)| size.abs_diff(target)),
+            SizePolicy::Min(target) => sized
+                .filter(|(size, _)| *size >= target)
+                .min_by_key(|(size, _)| *size),
+        };
+        selected.map(|(_, image)| image)
+    }
+}
+
+impl fmt::Display for SizePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SizePolicy::Largest => write!(f, "largest"),
+            SizePolicy::Smallest => write!(f, "smallest"),
+            SizePolicy::Closest(size) => write!(f, "closest:{size}"),
+            SizePolicy::Min(size) => write!(f, "min:{size}"),
+            SizePolicy::Original => write!(f, "original"),
+        }
+    }
+}
+
+impl FromStr for SizePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pixels = |value: &str| {
+            value
+                .parse::<u32>()
+                .map_err(|_| format!("`{value}` is not a number of pixels"))
+        };
+        match s.split_once(':') {
+            None if s == "largest" => Ok(SizePolicy::Largest),
+            None if s == "smallest" => Ok(SizePolicy::Smallest),
+            None if s == "original" => Ok(SizePolicy::Original),
+            Some(("closest", size)) => Ok(SizePolicy::Closest(pixels(size)?)),
+            Some(("min", size)) => Ok(SizePolicy::Min(pixels(size)?)),
+            _ => Err(format!(
+                "unknown size `{s}`, expected largest, smallest, original, closest:<px> or min:<px>"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn images(sizes: &[Option<u32>]) -> Vec<CoverImage> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(i, size)| CoverImage {
+                url: format!("/images/{i}.jpg"),
+                width: *size,
+                height: *size,
+            })
+            .collect()
+    }
+
+    fn selected(policy: SizePolicy, sizes: &[Option<u32>]) -> Option<Option<u32>> {
+        policy.select(&images(sizes)).map(|image| image.size())
+    }
+
+    #[test]
+    fn selects_by_policy() {
+        let sizes = [Some(300), Some(640), Some(64)];
+        assert_eq!(selected(SizePolicy::Largest, &sizes), Some(Some(640)));
+        assert_eq!(selected(SizePolicy::Smallest, &sizes), Some(Some(64)));
+        assert_eq!(selected(SizePolicy::Closest(200), &sizes), Some(Some(300)));
+        assert_eq!(selected(SizePolicy::Closest(100), &sizes), Some(Some(64)));
+        assert_eq!(selected(SizePolicy::Min(301), &sizes), Some(Some(640)));
+        assert_eq!(selected(SizePolicy::Min(1000), &sizes), None);
+    }
+
+    #[test]
+    fn images_without_dimensions() {
+        let sizes = [None, Some(300)];
+        assert_eq!(selected(SizePolicy::Smallest, &sizes), Some(Some(300)));
+        assert_eq!(selected(SizePolicy::Largest, &[None, None]), Some(None));
+        assert_eq!(selected(SizePolicy::Min(1), &[None]), None);
+        assert_eq!(selected(SizePolicy::Largest, &[]), None);
+        assert_eq!(selected(SizePolicy::Original, &sizes), Some(None));
+        assert_eq!(
+            selected(SizePolicy::Original, &[Some(64), Some(300)]),
+            Some(Some(300))
+        );
+    }
+
+    #[test]
+    fn parses_policies() {
+        for policy in [
+            SizePolicy::Largest,
+            SizePolicy::Smallest,
+            SizePolicy::Closest(300),
+            SizePolicy::Min(500),
+            SizePolicy::Original,
+        ] {
+            assert_eq!(policy.to_string().parse(), Ok(policy));
+        }
+        assert!("closest".parse::<SizePolicy>().is_err());
+        assert!("min:big".parse::<SizePolicy>().is_err());
+        assert!("huge".parse::<SizePolicy>().is_err());
+    }
+}
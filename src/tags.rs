@@ -0,0 +1,223 @@
+use crate::error::{Error, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// The tags of an audio file that are used to look up its cover
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackInfo {
+    pub title: String,
+    pub artists: Vec<String>,
+    pub album: String,
+    pub album_artist: Option<String>,
+    /// The MusicBrainz ID of the release the track is from
+    pub musicbrainz_release_id: Option<String>,
+}
+
+fn read_tag(filename: &Path) -> Result<Box<dyn audiotags::AudioTag + Send + Sync>> {
+    match audiotags::Tag::new().read_from_path(filename) {
+        Ok(tag) => Ok(tag),
+        Err(audiotags::Error::UnknownFileExtension(_) | audiotags::Error::UnsupportedFormat(_)) => {
+            Err(Error::InvalidFiletype(filename.to_path_buf()))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Whether the tags of `filename` already contain cover art
+pub fn has_embedded_cover(filename: impl AsRef<Path>) -> Result<bool> {
+    Ok(read_tag(filename.as_ref())?.album_cover().is_some())
+}
+
+/// Writes `image_data` into the tags of `filename` as its front cover, replacing any existing one
+pub fn embed_cover(filename: impl AsRef<Path>, image_data: &[u8]) -> Result<()> {
+    let filename = filename.as_ref();
+    let mime_type = if image_data.starts_with(b"\xff\xd8\xff") {
+        audiotags::MimeType::Jpeg
+    } else if image_data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        audiotags::MimeType::Png
+    } else {
+        return Err(Error::UnsupportedImage);
+    };
+    let path = filename
+        .to_str()
+        .ok_or(Error::InvalidFiletype(filename.to_path_buf()))?;
+
+    let mut tag = read_tag(filename)?;
+    tag.set_album_cover(audiotags::Picture::new(image_data, mime_type));
+    tag.write_to_path(path)?;
+    Ok(())
+}
+
+impl TrackInfo {
+    pub fn from_file(filename: impl AsRef<Path>) -> Result<Self> {
+        let tag = read_tag(filename.as_ref())?;
+
+        Ok(Self {
+            title: tag.title().ok_or(Error::MissingTag("title"))?.to_string(),
+            artists: tag
+                .artist()
+                .ok_or(Error::MissingTag("artist"))?
+                .split(", ")
+                .map(String::from)
+                .collect(),
+            album: tag
+                .album_title()
+                .ok_or(Error::MissingTag("album"))?
+                .to_string(),
+            album_artist: tag.album_artist().map(String::from),
+            musicbrainz_release_id: musicbrainz_release_id(filename.as_ref()),
+        })
+    }
+
+    pub fn artist_names(&self) -> Vec<&str> {
+        self.artists.iter().map(String::as_str).collect()
+    }
+}
+
+/// The album that most tracks in a directory agree on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlbumInfo {
+    pub album: String,
+    pub album_artist: String,
+    pub musicbrainz_release_id: Option<String>,
+}
+
+impl AlbumInfo {
+    /// Takes the most common album title, album artist and release ID among `tracks`. Tracks
+    /// without an album artist tag count towards their first artist instead.
+    pub fn from_tracks(tracks: &[TrackInfo]) -> Option<Self> {
+        let album = most_common(tracks.iter().map(|track| track.album.as_str()))?;
+        let album_tracks = tracks.iter().filter(|track| track.album == album);
+        let musicbrainz_release_id = most_common(
+            album_tracks
+                .clone()
+                .filter_map(|track| track.musicbrainz_release_id.as_deref()),
+        );
+        let album_artist = most_common(album_tracks.filter_map(|track| {
+            track
+                .album_artist
+                .as_deref()
+                .or(track.artists.first().map(String::as_str))
+        }))?;
+
+        Some(Self {
+            album: album.to_string(),
+            album_artist: album_artist.to_string(),
+            musicbrainz_release_id: musicbrainz_release_id.map(String::from),
+        })
+    }
+}
+
+/// The name of the freeform MP4 atom that taggers like Picard store the release ID in
+const MP4_RELEASE_ID: mp4ameta::FreeformIdent<'static> =
+    mp4ameta::FreeformIdent::new("com.apple.iTunes", "MusicBrainz Album Id");
+
+/// The MusicBrainz release ID that taggers like Picard write, which audiotags doesn't expose
+fn musicbrainz_release_id(filename: &Path) -> Option<String> {
+    let extension = filename.extension()?.to_str()?.to_ascii_lowercase();
+    let release_id = match extension.as_str() {
+        "mp3" => id3::Tag::read_from_path(filename)
+            .ok()?
+            .extended_texts()
+            .find(|text| text.description == "MusicBrainz Album Id")?
+            .value
+            .clone(),
+        "flac" => metaflac::Tag::read_from_path(filename)
+            .ok()?
+            .get_vorbis("MUSICBRAINZ_ALBUMID")?
+            .next()?
+            .to_string(),
+        "m4a" | "m4b" | "m4p" | "m4v" | "isom" | "mp4" => mp4ameta::Tag::read_from_path(filename)
+            .ok()?
+            .strings_of(&MP4_RELEASE_ID)
+            .next()?
+            .to_string(),
+        _ => return None,
+    };
+    Some(release_id.trim().to_string()).filter(|release_id| !release_id.is_empty())
+}
+
+/// The most frequent non-empty value, preferring the one that sorts first on ties
+fn most_common<'a>(values: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for value in values.filter(|value| !value.trim().is_empty()) {
+        *counts.entry(value).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .rev()
+        .max_by_key(|(_, count)| *count)
+        .map(|(value, _)| value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn track(album: &str, artist: &str, album_artist: Option<&str>) -> TrackInfo {
+        TrackInfo {
+            title: "Title".to_string(),
+            artists: vec![artist.to_string()],
+            album: album.to_string(),
+            album_artist: album_artist.map(String::from),
+            musicbrainz_release_id: None,
+        }
+    }
+
+    #[test]
+    fn album_consensus_uses_most_common_values() {
+        let tracks = [
+            track("A Night at the Opera", "Queen", None),
+            track("A Night at the Opera", "Queen", None),
+            track("A Night At The Opera (Remastered)", "Queen", None),
+        ];
+        assert_eq!(
+            AlbumInfo::from_tracks(&tracks),
+            Some(AlbumInfo {
+                album: "A Night at the Opera".to_string(),
+                album_artist: "Queen".to_string(),
+                musicbrainz_release_id: None,
+            })
+        );
+    }
+
+    #[test]
+    fn album_consensus_prefers_album_artist() {
+        let tracks = [
+            track("Now 42", "Artist A", Some("Various Artists")),
+            track("Now 42", "Artist B", Some("Various Artists")),
+            track("Now 42", "Artist C", None),
+        ];
+        assert_eq!(
+            AlbumInfo::from_tracks(&tracks).unwrap().album_artist,
+            "Various Artists"
+        );
+    }
+
+    #[test]
+    fn album_consensus_ties_are_deterministic() {
+        let tracks = [track("B", "Queen", None), track("A", "Queen", None)];
+        assert_eq!(AlbumInfo::from_tracks(&tracks).unwrap().album, "A");
+    }
+
+    #[test]
+    fn album_consensus_needs_tracks() {
+        assert_eq!(AlbumInfo::from_tracks(&[]), None);
+    }
+
+    #[test]
+    fn reads_release_id_from_flac() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("track.flac");
+        let mut tag = metaflac::Tag::new();
+        tag.set_vorbis("TITLE", vec!["Bohemian Rhapsody"]);
+        tag.set_vorbis("musicbrainz_albumid", vec![" a1b2 "]);
+        tag.write_to_path(&path).unwrap();
+
+        assert_eq!(musicbrainz_release_id(&path).as_deref(), Some("a1b2"));
+        assert_eq!(
+            musicbrainz_release_id(&dir.path().join("missing.flac")),
+            None
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use crate::tags::{AlbumInfo, TrackInfo};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Placeholders that can be used in an output template
+const FIELDS: [&str; 4] = ["album", "albumartist", "artist", "ext"];
+
+/// Extensions that `{ext}` can stand for
+const EXTENSIONS: [&str; 3] = ["jpg", "png", "webp"];
+
+/// The values that the placeholders of an `OutputTemplate` are replaced with
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TemplateFields {
+    pub album: String,
+    pub album_artist: String,
+    pub artist: String,
+}
+
+impl TemplateFields {
+    pub fn from_track(track: &TrackInfo) -> Self {
+        let artist = track.artists.first().cloned().unwrap_or_default();
+        Self {
+            album: track.album.clone(),
+            album_artist: track.album_artist.clone().unwrap_or(artist.clone()),
+            artist,
+        }
+    }
+
+    pub fn from_album(album: &AlbumInfo) -> Self {
+        Self {
+            album: album.album.clone(),
+            album_artist: album.album_artist.clone(),
+            artist: album.album_artist.clone(),
+        }
+    }
+}
+
+/// An output path with placeholders for tag fields and the image's extension, e.g.
+/// `../art/{albumartist} - {album}.{ext}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputTemplate(String);
+
+impl OutputTemplate {
+    /// Fills in the tag fields, sanitized so they can't add path components. `{ext}` is left
+    /// as is, since the format is only known once the image has been downloaded.
+    pub fn render(&self, fields: &TemplateFields) -> PathBuf {
+        let mut rendered = String::new();
+        let mut rest = self.0.as_str();
+        while let Some(start) = rest.find('{') {
+            let end = start + rest[start..].find('}').expect("Templates are validated");
+            rendered.push_str(&rest[..start]);
+            match &rest[start + 1..end] {
+                "album" => rendered.push_str(&sanitize(&fields.album)),
+                "albumartist" => rendered.push_str(&sanitize(&fields.album_artist)),
+                "artist" => rendered.push_str(&sanitize(&fields.artist)),
+                _ => rendered.push_str(&rest[start..=end]),
+            }
+            rest = &rest[end + 1..];
+        }
+        rendered.push_str(rest);
+        PathBuf::from(rendered)
+    }
+}
+
+/// Replaces the `{ext}` left in a rendered path, and an image extension that doesn't match
+/// `extension`, so that `cover.jpg` becomes `cover.png` for a PNG image
+pub fn with_extension(path: &Path, extension: &str) -> PathBuf {
+    let path = PathBuf::from(path.to_string_lossy().replace("{ext}", extension));
+    match image_extension(&path) {
+        Some(current) if current != extension => path.with_extension(extension),
+        _ => path,
+    }
+}
+
+/// The extension of `path` if it is one of `EXTENSIONS`, with `jpeg` counting as `jpg`
+fn image_extension(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    let extension = if extension == "jpeg" {
+        "jpg"
+    } else {
+        &extension
+    };
+    EXTENSIONS.into_iter().find(|known| *known == extension)
+}
+
+/// Every path a rendered path can end up as, one per extension if it still contains `{ext}` or
+/// ends in an image extension
+pub fn possible_paths(path: &Path) -> Vec<PathBuf> {
+    if !path.to_string_lossy().contains("{ext}") && image_extension(path).is_none() {
+        return vec![path.to_path_buf()];
+    }
+    EXTENSIONS
+        .iter()
+        .map(|extension| with_extension(path, extension))
+        .collect()
+}
+
+/// Makes a tag value safe to use as (part of) a file name: replaces characters that are not
+/// allowed or have a special meaning in paths, and keeps everything else as it is
+fn sanitize(value: &str) -> String {
+    let value: String = value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' | '{' | '}' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let value = value.trim_matches(|c: char| c == '.' || c.is_whitespace());
+    if value.is_empty() {
+        "_".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for OutputTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for OutputTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s;
+        while let Some(start) = rest.find(['{', '}']) {
+            if rest[start..].starts_with('}') {
+                return Err(format!("unmatched `}}` in `{s}`"));
+            }
+            let end = start
+                + rest[start..]
+                    .find('}')
+                    .ok_or(format!("unmatched `{{` in `{s}`"))?;
+            let field = &rest[start + 1..end];
+            if !FIELDS.contains(&field) {
+                return Err(format!(
+                    "unknown field `{{{field}}}`, expected one of {}",
+                    FIELDS.map(|field| format!("{{{field}}}")).join(", ")
+                ));
+            }
+            rest = &rest[end + 1..];
+        }
+        if s.is_empty() {
+            return Err("the output path can't be empty".to_string());
+        }
+        Ok(Self(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fields() -> TemplateFields {
+        TemplateFields {
+            album: "AC/DC: Live?".to_string(),
+            album_artist: "AC/DC".to_string(),
+            artist: "..".to_string(),
+        }
+    }
+
+    #[test]
+    fn renders_sanitized_fields() {
+        let template: OutputTemplate = "../art/{albumartist} - {album}.{ext}".parse().unwrap();
+        assert_eq!(
+            template.render(&fields()),
+            PathBuf::from("../art/AC_DC - AC_DC_ Live_.{ext}")
+        );
+        let template: OutputTemplate = "{artist}/cover.jpg".parse().unwrap();
+        assert_eq!(template.render(&fields()), PathBuf::from("_/cover.jpg"));
+    }
+
+    #[test]
+    fn keeps_non_ascii_fields() {
+        let fields = TemplateFields {
+            album: "Með suð í eyrum við spilum endalaust".to_string(),
+            album_artist: "Sigur Rós".to_string(),
+            artist: "坂本龍一".to_string(),
+        };
+        let template: OutputTemplate = "{albumartist}/{artist} - {album}.jpg".parse().unwrap();
+        assert_eq!(
+            template.render(&fields),
+            PathBuf::from("Sigur Rós/坂本龍一 - Með suð í eyrum við spilum endalaust.jpg")
+        );
+    }
+
+    #[test]
+    fn fills_in_extension() {
+        let path = PathBuf::from("art/folder.{ext}");
+        assert_eq!(
+            with_extension(&path, "png"),
+            PathBuf::from("art/folder.png")
+        );
+        assert_eq!(possible_paths(&path).len(), EXTENSIONS.len());
+        assert_eq!(
+            with_extension(Path::new("cover.jpg"), "png"),
+            PathBuf::from("cover.png")
+        );
+        assert_eq!(
+            with_extension(Path::new("cover.JPEG"), "jpg"),
+            PathBuf::from("cover.JPEG")
+        );
+        assert_eq!(
+            with_extension(Path::new("cover.v2"), "png"),
+            PathBuf::from("cover.v2")
+        );
+        assert_eq!(
+            possible_paths(Path::new("cover.jpg")),
+            vec![
+                PathBuf::from("cover.jpg"),
+                PathBuf::from("cover.png"),
+                PathBuf::from("cover.webp")
+            ]
+        );
+        assert_eq!(
+            possible_paths(Path::new("cover.v2")),
+            vec![PathBuf::from("cover.v2")]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        assert!("cover.jpg".parse::<OutputTemplate>().is_ok());
+        assert!("{title}.jpg".parse::<OutputTemplate>().is_err());
+        assert!("{album.jpg".parse::<OutputTemplate>().is_err());
+        assert!("album}.jpg".parse::<OutputTemplate>().is_err());
+        assert!("".parse::<OutputTemplate>().is_err());
+    }
+}
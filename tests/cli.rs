@@ -0,0 +1,1110 @@
+mod common;
+
+use anyhow::Result;
+use common::{FakeSpotify, Response};
+use id3::TagLike;
+use std::fs;
+use std::path::Path;
+use std::process::Output;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tempfile::TempDir;
+
+/// A home directory with credentials in `~/.config/spotify-image-search`
+fn home() -> TempDir {
+    let home = tempfile::tempdir().unwrap();
+    let config_home = home.path().join(".config/spotify-image-search");
+    fs::create_dir_all(&config_home).unwrap();
+    fs::write(config_home.join("client_id"), "test-client-id\n").unwrap();
+    fs::write(config_home.join("client_secret"), "test-client-secret\n").unwrap();
+    home
+}
+
+async fn run(server: &FakeSpotify, home: &Path, args: &[&str]) -> Output {
+    run_with_env(server, home, &[], args).await
+}
+
+/// Runs the binary in `home`, so that a .env file there is picked up
+async fn run_with_env(
+    server: &FakeSpotify,
+    home: &Path,
+    env: &[(&str, &str)],
+    args: &[&str],
+) -> Output {
+    tokio::process::Command::new(env!("CARGO_BIN_EXE_spotify-image-search"))
+        .current_dir(home)
+        .env("HOME", home)
+        .env_remove("SPOTIFY_AUTH_URL")
+        .env_remove("SPOTIFY_API_URL")
+        .env_remove("SPOTIFY_CLIENT_ID")
+        .env_remove("SPOTIFY_CLIENT_SECRET")
+        .envs(env.iter().copied())
+        .args(["--auth-url", &server.url, "--api-url", &server.url])
+        .args(args)
+        .output()
+        .await
+        .expect("Should be able to run the binary")
+}
+
+#[tokio::test]
+async fn cover_is_written_next_to_file() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("Queen/01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+
+    let output = run(&server, home.path(), &[track.to_str().unwrap()]).await;
+
+    assert!(output.status.success());
+    let cover = fs::read(music.path().join("Queen/cover.jpg"))?;
+    assert_eq!(cover, common::IMAGE_DATA);
+    assert_eq!(
+        server
+            .requests_to("/images/a-night-at-the-opera-640.jpg")
+            .len(),
+        1
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn existing_cover_is_kept_without_force() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+    fs::write(music.path().join("cover.jpg"), "original")?;
+
+    let output = run(&server, home.path(), &[track.to_str().unwrap()]).await;
+    assert!(!output.status.success());
+    assert_eq!(fs::read(music.path().join("cover.jpg"))?, b"original");
+
+    let output = run(&server, home.path(), &["--force", track.to_str().unwrap()]).await;
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read(music.path().join("cover.jpg"))?,
+        common::IMAGE_DATA
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn several_sizes_are_written() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+
+    let output = run(
+        &server,
+        home.path(),
+        &[
+            "--output",
+            "cover.jpg",
+            "--output",
+            "thumb.jpg",
+            "--size",
+            "largest",
+            "--size",
+            "smallest",
+            track.to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    assert!(output.status.success());
+    assert!(music.path().join("cover.jpg").exists());
+    assert!(music.path().join("thumb.jpg").exists());
+    assert_eq!(
+        server
+            .requests_to("/images/a-night-at-the-opera-640.jpg")
+            .len(),
+        1
+    );
+    assert_eq!(
+        server
+            .requests_to("/images/a-night-at-the-opera-64.jpg")
+            .len(),
+        1
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn cover_is_processed_before_writing() -> Result<()> {
+    let server = FakeSpotify::with_handler(|request| {
+        if request.path.starts_with("/images/") {
+            let mut png = Vec::new();
+            image::DynamicImage::new_rgb8(640, 640)
+                .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+                .unwrap();
+            return Response::new(200, "image/png", png);
+        }
+        common::default_response(request)
+    })
+    .await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+
+    let output = run(
+        &server,
+        home.path(),
+        &[
+            "--max-size",
+            "100",
+            "--format",
+            "jpeg",
+            "--quality",
+            "80",
+            track.to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    assert!(output.status.success());
+    let cover = fs::read(music.path().join("cover.jpg"))?;
+    assert_eq!(image::guess_format(&cover)?, image::ImageFormat::Jpeg);
+    let cover = image::load_from_memory(&cover)?;
+    assert_eq!((cover.width(), cover.height()), (100, 100));
+    Ok(())
+}
+
+#[tokio::test]
+async fn image_extension_matches_the_format() -> Result<()> {
+    let server = FakeSpotify::with_handler(|request| {
+        if request.path.starts_with("/images/") {
+            let mut png = Vec::new();
+            image::DynamicImage::new_rgb8(64, 64)
+                .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+                .unwrap();
+            return Response::new(200, "image/png", png);
+        }
+        common::default_response(request)
+    })
+    .await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+
+    let output = run(&server, home.path(), &[track.to_str().unwrap()]).await;
+
+    assert!(output.status.success(), "{output:?}");
+    let cover = fs::read(music.path().join("cover.png"))?;
+    assert_eq!(image::guess_format(&cover)?, image::ImageFormat::Png);
+    assert!(!music.path().join("cover.jpg").exists());
+
+    // The existing cover.png counts as the cover that would be written to cover.jpg
+    let output = run(
+        &server,
+        home.path(),
+        &["--recursive", music.path().to_str().unwrap()],
+    )
+    .await;
+    assert!(output.status.success(), "{output:?}");
+    assert!(!music.path().join("cover.jpg").exists());
+    Ok(())
+}
+
+#[tokio::test]
+async fn failed_download_keeps_existing_cover() -> Result<()> {
+    let server = FakeSpotify::with_handler(|request| {
+        if request.path.starts_with("/images/") {
+            return Response::new(200, "text/html", "<html>Oops</html>");
+        }
+        common::default_response(request)
+    })
+    .await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+    fs::write(music.path().join("cover.jpg"), "original")?;
+
+    let output = run(&server, home.path(), &["--force", track.to_str().unwrap()]).await;
+
+    assert_eq!(output.status.code(), Some(7));
+    assert_eq!(fs::read(music.path().join("cover.jpg"))?, b"original");
+    assert_eq!(fs::read_dir(music.path())?.count(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn output_template_is_filled_in() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("Queen/01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+
+    let output = run(
+        &server,
+        home.path(),
+        &[
+            "--output",
+            "../art/{albumartist} - {album}.{ext}",
+            track.to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read(music.path().join("art/Queen - A Night at the Opera.jpg"))?,
+        common::IMAGE_DATA
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn recursive_run_writes_one_cover_per_directory() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    common::write_track(
+        &music.path().join("Opera/01.mp3"),
+        "Bohemian Rhapsody",
+        "Queen",
+        "A Night at the Opera",
+    );
+    common::write_track(
+        &music.path().join("Opera/02.mp3"),
+        "Bohemian Rhapsody",
+        "Queen",
+        "A Night at the Opera",
+    );
+    common::write_track(
+        &music.path().join("Hits/01.mp3"),
+        "Bohemian Rhapsody",
+        "Queen",
+        "Greatest Hits",
+    );
+    fs::write(music.path().join("Hits/notes.txt"), "not music")?;
+
+    let output = run(
+        &server,
+        home.path(),
+        &[
+            "--recursive",
+            "--output",
+            "folder.jpg",
+            music.path().to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    assert!(output.status.success());
+    assert!(music.path().join("Opera/folder.jpg").exists());
+    assert!(music.path().join("Hits/folder.jpg").exists());
+    assert!(!music.path().join("folder.jpg").exists());
+    assert_eq!(server.requests_to("/api/token").len(), 1);
+    assert_eq!(server.requests_to("/v1/search").len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn concurrent_jobs_keep_output_in_order() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    let directories = ["A", "B", "C", "D", "E"];
+    for directory in directories {
+        common::write_track(
+            &music.path().join(directory).join("01.mp3"),
+            "Bohemian Rhapsody",
+            "Queen",
+            "A Night at the Opera",
+        );
+    }
+
+    let output = run(
+        &server,
+        home.path(),
+        &["--recursive", "--jobs", "4", music.path().to_str().unwrap()],
+    )
+    .await;
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let written: Vec<_> = stderr
+        .lines()
+        .filter_map(|line| line.strip_prefix("SPOT_IMG_SEARCH: Writing to file: "))
+        .collect();
+    let expected: Vec<_> = directories
+        .iter()
+        .map(|directory| music.path().join(directory).join("cover.jpg"))
+        .collect();
+    assert_eq!(
+        written,
+        expected
+            .iter()
+            .map(|path| path.to_str().unwrap())
+            .collect::<Vec<_>>()
+    );
+    assert!(expected.iter().all(|path| path.exists()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn album_mode_searches_once_per_directory() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    for (index, title) in [
+        "Death on Two Legs",
+        "Lazing on a Sunday Afternoon",
+        "Bohemian Rhapsody",
+    ]
+    .iter()
+    .enumerate()
+    {
+        common::write_track(
+            &music.path().join(format!("Opera/{index:02}.mp3")),
+            title,
+            "Queen",
+            "A Night at the Opera",
+        );
+    }
+    common::write_track(
+        &music.path().join("Hits/01.mp3"),
+        "Bohemian Rhapsody",
+        "Queen",
+        "Greatest Hits",
+    );
+
+    let output = run(
+        &server,
+        home.path(),
+        &["--recursive", "--album", music.path().to_str().unwrap()],
+    )
+    .await;
+
+    assert!(output.status.success());
+    assert!(music.path().join("Opera/cover.jpg").exists());
+    assert!(music.path().join("Hits/cover.jpg").exists());
+    let searches = server.requests_to("/v1/search");
+    assert_eq!(searches.len(), 2);
+    assert!(searches
+        .iter()
+        .all(|search| search.query.contains("type=album")));
+    assert_eq!(
+        server
+            .requests_to("/images/a-night-at-the-opera-640.jpg")
+            .len(),
+        1
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn dry_run_reports_plan_without_writing() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    common::write_track(
+        &music.path().join("Opera/01.mp3"),
+        "Bohemian Rhapsody",
+        "Queen",
+        "A Night at the Opera",
+    );
+    common::write_track(
+        &music.path().join("Hits/01.mp3"),
+        "Bohemian Rhapsody",
+        "Queen",
+        "Greatest Hits",
+    );
+    fs::write(music.path().join("Hits/cover.jpg"), "original")?;
+    // Would be dropped if the cache was compacted
+    let lookup_cache = home
+        .path()
+        .join(".config/spotify-image-search/lookup_cache.jsonl");
+    fs::write(&lookup_cache, "cut off\n")?;
+
+    let output = run(
+        &server,
+        home.path(),
+        &[
+            "--recursive",
+            "--force",
+            "--dry-run",
+            "--quiet",
+            music.path().to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    assert!(output.status.success());
+    // The plan is output, not a diagnostic, so --quiet doesn't hide it
+    assert!(output.stderr.is_empty(), "{output:?}");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Match: Bohemian Rhapsody by Queen from A Night at the Opera"));
+    assert!(stdout.contains(&format!(
+        "Image: {}/images/a-night-at-the-opera-640.jpg",
+        server.url
+    )));
+    assert!(stdout.contains(&format!(
+        "Destination: {}",
+        music.path().join("Opera/cover.jpg").display()
+    )));
+    assert!(stdout.contains(&format!(
+        "Destination: {} (exists, would be overwritten)",
+        music.path().join("Hits/cover.jpg").display()
+    )));
+    assert!(!music.path().join("Opera/cover.jpg").exists());
+    assert_eq!(fs::read(music.path().join("Hits/cover.jpg"))?, b"original");
+    assert_eq!(fs::read_to_string(&lookup_cache)?, "cut off\n");
+    assert!(!lookup_cache.with_file_name("token.json").exists());
+    assert!(server
+        .requests()
+        .iter()
+        .all(|request| !request.path.starts_with("/images/")));
+    Ok(())
+}
+
+/// The data of the first picture embedded in `path`'s ID3 tag
+fn embedded_cover(path: &Path) -> Option<Vec<u8>> {
+    let tag = id3::Tag::read_from_path(path).ok()?;
+    let data = tag.pictures().next()?.data.clone();
+    Some(data)
+}
+
+#[tokio::test]
+async fn embed_writes_cover_into_tags() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    let bare = music.path().join("01.mp3");
+    let with_art = music.path().join("02.mp3");
+    common::write_track(&bare, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+    common::write_track(
+        &with_art,
+        "Bohemian Rhapsody",
+        "Queen",
+        "A Night at the Opera",
+    );
+    let mut tag = id3::Tag::read_from_path(&with_art)?;
+    tag.add_frame(id3::frame::Picture {
+        mime_type: "image/png".to_string(),
+        picture_type: id3::frame::PictureType::CoverFront,
+        description: String::new(),
+        data: b"original".to_vec(),
+    });
+    tag.write_to_path(&with_art, id3::Version::Id3v24)?;
+
+    let output = run(
+        &server,
+        home.path(),
+        &["--recursive", "--embed", music.path().to_str().unwrap()],
+    )
+    .await;
+    assert!(output.status.success());
+    assert_eq!(embedded_cover(&bare).as_deref(), Some(common::IMAGE_DATA));
+    assert_eq!(embedded_cover(&with_art).as_deref(), Some(&b"original"[..]));
+    assert!(music.path().join("cover.jpg").exists());
+
+    let output = run(
+        &server,
+        home.path(),
+        &[
+            "--recursive",
+            "--embed",
+            "--existing-art",
+            "replace",
+            music.path().to_str().unwrap(),
+        ],
+    )
+    .await;
+    assert!(output.status.success());
+    assert_eq!(
+        embedded_cover(&with_art).as_deref(),
+        Some(common::IMAGE_DATA)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn failed_lookups_are_retried_with_resume() -> Result<()> {
+    let hits_fail = Arc::new(AtomicBool::new(true));
+    let server = FakeSpotify::with_handler({
+        let hits_fail = hits_fail.clone();
+        move |request| {
+            if request.path == "/v1/search"
+                && request.query.contains("Greatest")
+                && hits_fail.load(Ordering::SeqCst)
+            {
+                return Response::json(500, r#"{"error":{"status":500,"message":"Oops"}}"#);
+            }
+            common::default_response(request)
+        }
+    })
+    .await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    common::write_track(
+        &music.path().join("Hits/01.mp3"),
+        "Bohemian Rhapsody",
+        "Queen",
+        "Greatest Hits",
+    );
+    common::write_track(
+        &music.path().join("Opera/01.mp3"),
+        "Bohemian Rhapsody",
+        "Queen",
+        "A Night at the Opera",
+    );
+    let args = [
+        "--recursive",
+        "--album",
+        "--force",
+        "--no-cache",
+        "--max-retries",
+        "0",
+        music.path().to_str().unwrap(),
+    ];
+
+    let output = run(&server, home.path(), &args).await;
+    assert_eq!(output.status.code(), Some(6));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Failed:"));
+    assert!(!music.path().join("Hits/cover.jpg").exists());
+    assert!(music.path().join("Opera/cover.jpg").exists());
+    let searches = server.requests_to("/v1/search").len();
+
+    hits_fail.store(false, Ordering::SeqCst);
+    let output = run(&server, home.path(), &[&["--resume"], &args[..]].concat()).await;
+    assert!(output.status.success());
+    assert!(music.path().join("Hits/cover.jpg").exists());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("done in an earlier run"));
+    assert_eq!(server.requests_to("/v1/search").len(), searches + 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn report_has_an_entry_per_file() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    for (path, album) in [
+        ("Opera/01.mp3", "A Night at the Opera"),
+        ("Opera/02.mp3", "A Night at the Opera"),
+        ("Hits/01.mp3", "Greatest Hits"),
+    ] {
+        common::write_track(
+            &music.path().join(path),
+            "Bohemian Rhapsody",
+            "Queen",
+            album,
+        );
+    }
+    let report = music.path().join("report.ndjson");
+
+    let output = run(
+        &server,
+        home.path(),
+        &[
+            "--recursive",
+            "--report",
+            "ndjson",
+            report.to_str().unwrap(),
+            music.path().to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    assert!(output.status.success());
+    let entries: Vec<serde_json::Value> = fs::read_to_string(&report)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    let items: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            (
+                entry["item"].as_str().unwrap(),
+                entry["status"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    let path = |path: &str| music.path().join(path).to_str().unwrap().to_string();
+    assert_eq!(
+        items,
+        [
+            (path("Hits/01.mp3").as_str(), "found"),
+            (path("Opera/01.mp3").as_str(), "found"),
+            (path("Opera/02.mp3").as_str(), "skipped"),
+        ]
+    );
+
+    let hits = &entries[0];
+    assert_eq!(hits["tags"]["album"], "Greatest Hits");
+    assert_eq!(hits["query"], "track:Bohemian Rhapsody artist:Queen");
+    assert_eq!(hits["match"]["album"], "Greatest Hits");
+    assert!(hits["candidates"].as_array().unwrap().len() > 1);
+    assert_eq!(hits["destinations"][0]["path"], path("Hits/cover.jpg"));
+    // The size of the image that was written, not the one the API claims
+    assert_eq!(hits["destinations"][0]["width"], 16);
+    assert!(hits["destinations"][0]["url"]
+        .as_str()
+        .unwrap()
+        .ends_with("/images/greatest-hits-640.jpg"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn json_report_records_errors() -> Result<()> {
+    let server = FakeSpotify::with_handler(common::with_search_results(common::SEARCH_EMPTY)).await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+    let report = music.path().join("report.json");
+
+    let output = run(
+        &server,
+        home.path(),
+        &[
+            "--report",
+            "json",
+            report.to_str().unwrap(),
+            track.to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    assert_eq!(output.status.code(), Some(8));
+    let entries: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report)?)?;
+    assert_eq!(entries.as_array().unwrap().len(), 1);
+    assert_eq!(entries[0]["status"], "no-match");
+    assert!(entries[0]["error"]
+        .as_str()
+        .unwrap()
+        .contains("Bohemian Rhapsody"));
+    assert_eq!(entries[0]["match"], serde_json::Value::Null);
+    Ok(())
+}
+
+#[tokio::test]
+async fn skipped_files_are_logged_by_level() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    common::write_track(
+        &music.path().join("Opera/01.mp3"),
+        "Bohemian Rhapsody",
+        "Queen",
+        "A Night at the Opera",
+    );
+    fs::write(music.path().join("Opera/cover.jpg"), "original")?;
+    fs::write(music.path().join("Opera/notes.txt"), "not music")?;
+    let log_file = music.path().join("run.log");
+
+    let output = run(
+        &server,
+        home.path(),
+        &["--recursive", music.path().to_str().unwrap()],
+    )
+    .await;
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(&format!(
+        "Skipping {}: it already exists",
+        music.path().join("Opera/cover.jpg").display()
+    )));
+    assert!(!stderr.contains("not an audio file"));
+
+    let output = run(
+        &server,
+        home.path(),
+        &[
+            "--recursive",
+            "--quiet",
+            "--log-file",
+            log_file.to_str().unwrap(),
+            music.path().to_str().unwrap(),
+        ],
+    )
+    .await;
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+    let log = fs::read_to_string(&log_file)?;
+    assert!(log.contains("INFO  Skipping"));
+    assert!(log.contains(&format!(
+        "DEBUG Skipping {}: not an audio file",
+        music.path().join("Opera/notes.txt").display()
+    )));
+    Ok(())
+}
+
+#[tokio::test]
+async fn access_token_is_cached_between_runs() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+
+    let args = ["--force", "--no-cache", track.to_str().unwrap()];
+    run(&server, home.path(), &args).await;
+    run(&server, home.path(), &args).await;
+
+    assert_eq!(server.requests_to("/api/token").len(), 1);
+    assert_eq!(server.requests_to("/v1/search").len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn lookups_are_cached_between_runs() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+
+    let output = run(&server, home.path(), &[track.to_str().unwrap()]).await;
+    assert!(output.status.success());
+    fs::remove_file(music.path().join("cover.jpg"))?;
+    let output = run(&server, home.path(), &[track.to_str().unwrap()]).await;
+    assert!(output.status.success());
+    assert!(music.path().join("cover.jpg").exists());
+    assert_eq!(server.requests_to("/v1/search").len(), 1);
+    let forever = [
+        "--force",
+        "--cache-ttl",
+        "999999999999999",
+        track.to_str().unwrap(),
+    ];
+    let output = run(&server, home.path(), &forever).await;
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(server.requests_to("/v1/search").len(), 1);
+
+    let refresh = ["--force", "--refresh-cache", track.to_str().unwrap()];
+    run(&server, home.path(), &refresh).await;
+    assert_eq!(server.requests_to("/v1/search").len(), 2);
+    let no_cache = ["--force", "--no-cache", track.to_str().unwrap()];
+    run(&server, home.path(), &no_cache).await;
+    assert_eq!(server.requests_to("/v1/search").len(), 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn lookups_are_cached_per_size() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+
+    let huge = ["--size", "min:5000", track.to_str().unwrap()];
+    let output = run(&server, home.path(), &huge).await;
+    assert_eq!(output.status.code(), Some(8), "{output:?}");
+    let output = run(&server, home.path(), &[track.to_str().unwrap()]).await;
+    assert!(output.status.success(), "{output:?}");
+    assert!(music.path().join("cover.jpg").exists());
+
+    let output = run(&server, home.path(), &huge).await;
+    assert_eq!(output.status.code(), Some(8), "{output:?}");
+    let searches = server.requests_to("/v1/search").len();
+    run(&server, home.path(), &huge).await;
+    assert_eq!(server.requests_to("/v1/search").len(), searches);
+    Ok(())
+}
+
+#[tokio::test]
+async fn untagged_file_exits_with_tag_error() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    let notes = music.path().join("notes.txt");
+    fs::write(&notes, "not music")?;
+
+    let output = run(&server, home.path(), &[notes.to_str().unwrap()]).await;
+
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid filetype"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn rejected_credentials_exit_with_auth_error() -> Result<()> {
+    let server = FakeSpotify::with_handler(|request| match request.path.as_str() {
+        "/api/token" => Response::json(400, r#"{"error":"invalid_client"}"#),
+        _ => common::default_response(request),
+    })
+    .await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+
+    let output = run(&server, home.path(), &[track.to_str().unwrap()]).await;
+
+    assert_eq!(output.status.code(), Some(4));
+    Ok(())
+}
+
+#[tokio::test]
+async fn invalid_argument_combinations_are_usage_errors() {
+    let server = FakeSpotify::start().await;
+    let home = home();
+    let music = tempfile::tempdir().unwrap();
+    let track = music.path().join("01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+
+    for args in [
+        &["--size", "largest", "--size", "smallest"][..],
+        &["--embed", "--format", "webp"],
+        &["--report", "xml", "report.xml"],
+        &["--report", "csv", "a.csv", "--report", "json", "b.json"],
+        &["--rate-limit", "1e-300"],
+        &["--rate-limit=-1"],
+        &["--rate-limit", "NaN"],
+    ] {
+        let mut args = args.to_vec();
+        args.push(track.to_str().unwrap());
+        let output = run(&server, home.path(), &args).await;
+
+        assert_eq!(output.status.code(), Some(2), "{args:?}: {output:?}");
+    }
+    assert!(server.requests().is_empty());
+}
+
+#[tokio::test]
+async fn directory_requires_recursive_flag() {
+    let server = FakeSpotify::start().await;
+    let home = home();
+    let music = tempfile::tempdir().unwrap();
+
+    let output = run(&server, home.path(), &[music.path().to_str().unwrap()]).await;
+
+    assert!(!output.status.success());
+    assert!(server.requests_to("/v1/search").is_empty());
+}
+
+#[tokio::test]
+async fn musicbrainz_is_used_by_release_id_without_credentials() -> Result<()> {
+    let server = FakeSpotify::with_handler(common::musicbrainz_response).await;
+    let home = tempfile::tempdir()?;
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+    let mut tag = id3::Tag::read_from_path(&track)?;
+    tag.add_frame(id3::frame::ExtendedText {
+        description: "MusicBrainz Album Id".to_string(),
+        value: "rel-opera".to_string(),
+    });
+    tag.write_to_path(&track, id3::Version::Id3v24)?;
+
+    let output = run(
+        &server,
+        home.path(),
+        &[
+            "--providers",
+            "musicbrainz",
+            "--musicbrainz-url",
+            &server.url,
+            "--cover-art-url",
+            &server.url,
+            track.to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        fs::read(music.path().join("cover.jpg"))?,
+        common::IMAGE_DATA
+    );
+    assert_eq!(server.requests_to("/ws/2/release/rel-opera").len(), 1);
+    assert!(server.requests_to("/ws/2/recording").is_empty());
+    assert_eq!(server.requests_to("/images/caa-opera-1200.jpg").len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn providers_are_tried_in_order() -> Result<()> {
+    let server = FakeSpotify::with_handler(|request| match request.path.as_str() {
+        "/v1/search" => Response::json(200, common::SEARCH_EMPTY),
+        "/api/token" => common::token_response(),
+        _ => common::musicbrainz_response(request),
+    })
+    .await;
+    let home = home();
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+
+    let output = run(
+        &server,
+        home.path(),
+        &[
+            "--providers",
+            "spotify,musicbrainz",
+            "--musicbrainz-url",
+            &server.url,
+            "--cover-art-url",
+            &server.url,
+            track.to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    assert!(output.status.success(), "{output:?}");
+    assert!(!server.requests_to("/v1/search").is_empty());
+    assert_eq!(server.requests_to("/ws/2/recording").len(), 1);
+    assert_eq!(server.requests_to("/images/caa-opera-1200.jpg").len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn deezer_is_used_without_credentials() -> Result<()> {
+    let server = FakeSpotify::with_handler(common::deezer_response).await;
+    let home = tempfile::tempdir()?;
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+
+    let output = run(
+        &server,
+        home.path(),
+        &[
+            "--providers",
+            "deezer",
+            "--deezer-url",
+            &server.url,
+            track.to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        fs::read(music.path().join("cover.jpg"))?,
+        common::IMAGE_DATA
+    );
+    assert_eq!(
+        server
+            .requests_to("/images/deezer-opera/1000x1000.jpg")
+            .len(),
+        1
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn credentials_are_read_from_the_environment() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let home = tempfile::tempdir()?;
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+
+    let output = run_with_env(
+        &server,
+        home.path(),
+        &[
+            ("SPOTIFY_CLIENT_ID", "env-client-id"),
+            ("SPOTIFY_CLIENT_SECRET", "env-client-secret"),
+        ],
+        &[track.to_str().unwrap()],
+    )
+    .await;
+
+    assert!(output.status.success(), "{output:?}");
+    let requests = server.requests_to("/api/token");
+    assert!(requests[0].body.contains("client_id=env-client-id"));
+    assert!(requests[0].body.contains("client_secret=env-client-secret"));
+    // The config directory doesn't exist yet, but the token is still cached
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("Could not cache"));
+    assert!(home
+        .path()
+        .join(".config/spotify-image-search/token.json")
+        .exists());
+    Ok(())
+}
+
+#[tokio::test]
+async fn credentials_are_read_from_dotenv() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let home = tempfile::tempdir()?;
+    fs::write(
+        home.path().join(".env"),
+        "SPOTIFY_CLIENT_ID=dotenv-client-id\nSPOTIFY_CLIENT_SECRET=dotenv-client-secret\n",
+    )?;
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+
+    // The environment takes precedence over .env
+    let output = run_with_env(
+        &server,
+        home.path(),
+        &[("SPOTIFY_CLIENT_ID", "env-client-id")],
+        &[track.to_str().unwrap()],
+    )
+    .await;
+
+    assert!(output.status.success(), "{output:?}");
+    let requests = server.requests_to("/api/token");
+    assert!(requests[0].body.contains("client_id=env-client-id"));
+    assert!(requests[0]
+        .body
+        .contains("client_secret=dotenv-client-secret"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn credential_files_take_precedence() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let home = home();
+    let credentials = tempfile::tempdir()?;
+    let client_id_file = credentials.path().join("id");
+    fs::write(&client_id_file, "file-client-id\n")?;
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+
+    let output = run_with_env(
+        &server,
+        home.path(),
+        &[("SPOTIFY_CLIENT_ID", "env-client-id")],
+        &[
+            "--client-id-file",
+            client_id_file.to_str().unwrap(),
+            track.to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    assert!(output.status.success(), "{output:?}");
+    let requests = server.requests_to("/api/token");
+    assert!(requests[0].body.contains("client_id=file-client-id"));
+    // The secret still comes from the config directory
+    assert!(requests[0]
+        .body
+        .contains("client_secret=test-client-secret"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn missing_credentials_explain_where_to_put_them() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let home = tempfile::tempdir()?;
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "A Night at the Opera");
+
+    let output = run(&server, home.path(), &[track.to_str().unwrap()]).await;
+
+    assert_eq!(output.status.code(), Some(4));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("SPOTIFY_CLIENT_ID"), "{stderr}");
+    assert!(stderr.contains("--client-id-file"), "{stderr}");
+    assert!(stderr.contains("client_secret"), "{stderr}");
+    assert!(server.requests_to("/api/token").is_empty());
+    Ok(())
+}
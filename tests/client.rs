@@ -0,0 +1,517 @@
+mod common;
+
+use anyhow::Result;
+use common::{FakeSpotify, Response};
+use spotify_image_search::{Error, RetryPolicy, SizePolicy, SpotifyClient};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+const MAX_RETRIES: u32 = 2;
+
+fn client(server: &FakeSpotify) -> SpotifyClient {
+    SpotifyClient::new("test-client-id", "test-client-secret")
+        .with_auth_url(&server.url)
+        .with_api_url(&server.url)
+        .with_retry_policy(RetryPolicy {
+            max_retries: MAX_RETRIES,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(20),
+        })
+}
+
+/// The happy path, except that the first `failures` searches get `failure` instead
+fn failing_searches(
+    failures: usize,
+    failure: impl Fn() -> Response + Send + Sync + 'static,
+) -> impl Fn(&common::Request) -> Response {
+    let searches = AtomicUsize::new(0);
+    move |request| {
+        if request.path == "/v1/search" && searches.fetch_add(1, Ordering::SeqCst) < failures {
+            return failure();
+        }
+        common::default_response(request)
+    }
+}
+
+fn cached_client(server: &FakeSpotify, cache_dir: &Path) -> SpotifyClient {
+    client(server).with_token_cache(cache_dir.join("token.json"))
+}
+
+#[tokio::test]
+async fn access_token_is_requested_with_client_credentials() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let access_token = client(&server).access_token().await?;
+
+    assert_eq!(access_token, common::ACCESS_TOKEN);
+    let requests = server.requests_to("/api/token");
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].method, "POST");
+    assert!(requests[0].body.contains("grant_type=client_credentials"));
+    assert!(requests[0].body.contains("client_id=test-client-id"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn access_token_fails_on_invalid_response() {
+    let server = FakeSpotify::with_handler(|_| {
+        Response::new(503, "text/html", "<html>Service Unavailable</html>")
+    })
+    .await;
+
+    let err = client(&server).access_token().await.unwrap_err();
+    assert!(matches!(err, Error::Auth(_)));
+}
+
+#[tokio::test]
+async fn rejected_credentials_are_an_auth_error() {
+    let server = FakeSpotify::with_handler(|_| {
+        Response::json(
+            400,
+            r#"{"error":"invalid_client","error_description":"Invalid client secret"}"#,
+        )
+    })
+    .await;
+
+    let err = client(&server).access_token().await.unwrap_err();
+    assert!(matches!(err, Error::Auth(description) if description == "Invalid client secret"));
+}
+
+#[tokio::test]
+async fn cached_access_token_is_reused() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let cache_dir = tempfile::tempdir()?;
+    cached_client(&server, cache_dir.path())
+        .access_token()
+        .await?;
+    cached_client(&server, cache_dir.path())
+        .access_token()
+        .await?;
+
+    assert_eq!(server.requests_to("/api/token").len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn cached_access_token_of_other_client_is_not_reused() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let cache_dir = tempfile::tempdir()?;
+    for (auth_url, client_id) in [
+        ("https://accounts.example.com", "test-client-id"),
+        (server.url.as_str(), "other-client-id"),
+    ] {
+        fs::write(
+            cache_dir.path().join("token.json"),
+            format!(
+                r#"{{"access_token":"other","expires_at":99999999999,"auth_url":"{auth_url}","client_id":"{client_id}"}}"#
+            ),
+        )?;
+        let access_token = cached_client(&server, cache_dir.path())
+            .access_token()
+            .await?;
+        assert_eq!(access_token, common::ACCESS_TOKEN);
+    }
+
+    assert_eq!(server.requests_to("/api/token").len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn expired_access_token_is_refreshed() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let cache_dir = tempfile::tempdir()?;
+    fs::write(
+        cache_dir.path().join("token.json"),
+        r#"{"access_token":"expired","expires_at":0}"#,
+    )?;
+
+    let access_token = cached_client(&server, cache_dir.path())
+        .access_token()
+        .await?;
+    assert_eq!(access_token, common::ACCESS_TOKEN);
+    assert_eq!(server.requests_to("/api/token").len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn search_sends_encoded_query_and_token() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    client(&server)
+        .search("Bohemian Rhapsody", &["Queen"])
+        .await?;
+
+    let requests = server.requests_to("/v1/search");
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].query.contains("track%3ABohemian%20Rhapsody"));
+    assert!(requests[0].query.contains("type=track"));
+    assert_eq!(
+        requests[0].header("authorization"),
+        Some(format!("Bearer {}", common::ACCESS_TOKEN).as_str())
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn search_refreshes_rejected_token() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let cache_dir = tempfile::tempdir()?;
+    fs::write(
+        cache_dir.path().join("token.json"),
+        format!(
+            r#"{{"access_token":"revoked","expires_at":99999999999,"auth_url":"{}","client_id":"test-client-id"}}"#,
+            server.url
+        ),
+    )?;
+
+    let res = cached_client(&server, cache_dir.path())
+        .search("Bohemian Rhapsody", &["Queen"])
+        .await?;
+
+    assert!(res["tracks"]["items"].is_array());
+    assert_eq!(server.requests_to("/api/token").len(), 1);
+    assert_eq!(server.requests_to("/v1/search").len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn rate_limited_search_reports_retry_after() {
+    let server = FakeSpotify::with_handler(|request| match request.path.as_str() {
+        "/v1/search" => Response::json(429, "{}").with_header("Retry-After", "7"),
+        _ => common::default_response(request),
+    })
+    .await;
+
+    let err = client(&server)
+        .search("Bohemian Rhapsody", &["Queen"])
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        Error::RateLimited { retry_after: Some(retry_after) } if retry_after.as_secs() == 7
+    ));
+}
+
+#[tokio::test]
+async fn rate_limited_search_is_retried() -> Result<()> {
+    let server = FakeSpotify::with_handler(failing_searches(2, || {
+        Response::json(429, "{}").with_header("Retry-After", "0")
+    }))
+    .await;
+
+    let res = client(&server)
+        .search("Bohemian Rhapsody", &["Queen"])
+        .await?;
+
+    assert!(res["tracks"]["items"].is_array());
+    assert_eq!(server.requests_to("/v1/search").len(), 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_errors_are_retried() -> Result<()> {
+    let server = FakeSpotify::with_handler(failing_searches(1, || {
+        Response::new(502, "text/html", "<html>Bad Gateway</html>")
+    }))
+    .await;
+
+    client(&server)
+        .search("Bohemian Rhapsody", &["Queen"])
+        .await?;
+
+    assert_eq!(server.requests_to("/v1/search").len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn dropped_responses_are_retried() -> Result<()> {
+    let server = FakeSpotify::with_handler(failing_searches(1, || {
+        Response::json(200, common::SEARCH_TRACKS).cut_off()
+    }))
+    .await;
+
+    let res = client(&server)
+        .search("Bohemian Rhapsody", &["Queen"])
+        .await?;
+
+    assert!(res["tracks"]["items"].is_array());
+    assert_eq!(server.requests_to("/v1/search").len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn concurrent_requests_share_the_rate_limit() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let client = client(&server).with_rate_limit(20.0);
+    client.access_token().await?;
+
+    let started = Instant::now();
+    let search = || client.search("Bohemian Rhapsody", &["Queen"]);
+    let results = tokio::join!(search(), search(), search(), search());
+    results.0?;
+    results.1?;
+    results.2?;
+    results.3?;
+
+    // The token request took the first slot, so the searches are spaced 50ms apart after it
+    assert!(started.elapsed() >= Duration::from_millis(150));
+    assert_eq!(server.requests_to("/v1/search").len(), 4);
+    Ok(())
+}
+
+#[tokio::test]
+async fn retries_give_up_after_max_retries() {
+    let server = FakeSpotify::with_handler(failing_searches(usize::MAX, || {
+        Response::new(500, "text/html", "<html>Internal Server Error</html>")
+    }))
+    .await;
+
+    let err = client(&server)
+        .search("Bohemian Rhapsody", &["Queen"])
+        .await
+        .unwrap_err();
+
+    assert!(matches!(err, Error::HttpStatus { status, .. } if status.as_u16() == 500));
+    assert_eq!(
+        server.requests_to("/v1/search").len(),
+        MAX_RETRIES as usize + 1
+    );
+}
+
+#[tokio::test]
+async fn client_errors_are_not_retried() {
+    let server = FakeSpotify::with_handler(failing_searches(usize::MAX, || {
+        Response::json(400, r#"{"error":{"status":400,"message":"Bad request"}}"#)
+    }))
+    .await;
+
+    let err = client(&server)
+        .search("Bohemian Rhapsody", &["Queen"])
+        .await
+        .unwrap_err();
+
+    assert!(matches!(err, Error::HttpStatus { status, .. } if status.as_u16() == 400));
+    assert_eq!(server.requests_to("/v1/search").len(), 1);
+}
+
+#[tokio::test]
+async fn empty_search_falls_back_to_broader_query() -> Result<()> {
+    let server = FakeSpotify::with_handler(|request| {
+        if request.path == "/v1/search" && request.query.contains("artist%3A") {
+            return Response::json(200, common::SEARCH_EMPTY);
+        }
+        common::default_response(request)
+    })
+    .await;
+
+    let image_url = client(&server)
+        .get_image_url_for_track(
+            "Bohemian Rhapsody",
+            &["Queen & Friends"],
+            "A Night at the Opera",
+        )
+        .await?;
+
+    assert_eq!(
+        image_url,
+        format!("{}/images/a-night-at-the-opera-640.jpg", server.url)
+    );
+    let searches = server.requests_to("/v1/search");
+    assert_eq!(searches.len(), 2);
+    assert!(searches[0].query.contains("artist%3AQueen%20%26%20Friends"));
+    assert!(searches[1]
+        .query
+        .contains("album%3AA%20Night%20at%20the%20Opera"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn search_without_results_is_no_match() {
+    let server = FakeSpotify::with_handler(common::with_search_results(common::SEARCH_EMPTY)).await;
+
+    let err = client(&server)
+        .get_image_url_for_track("Bohemian Rhapsody", &["Queen"], "A Night at the Opera")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::NoMatch(_)));
+}
+
+#[tokio::test]
+async fn malformed_results_are_skipped() -> Result<()> {
+    let server =
+        FakeSpotify::with_handler(common::with_search_results(common::SEARCH_MALFORMED)).await;
+
+    let image_url = client(&server)
+        .get_image_url_for_track("Bohemian Rhapsody", &["Queen"], "A Night at the Opera")
+        .await?;
+
+    assert_eq!(
+        image_url,
+        format!("{}/images/greatest-hits-640.jpg", server.url)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn albums_without_images_are_no_match() {
+    let server =
+        FakeSpotify::with_handler(common::with_search_results(common::SEARCH_NO_IMAGES)).await;
+
+    let err = client(&server)
+        .get_image_url_for_track("Bohemian Rhapsody", &["Queen"], "A Night at the Opera")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::NoMatch(_)));
+}
+
+#[tokio::test]
+async fn best_matching_track_is_chosen() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let image_url = client(&server)
+        .get_image_url_for_track("Bohemian Rhapsody", &["Queen"], "A Night at the Opera")
+        .await?;
+
+    assert_eq!(
+        image_url,
+        format!("{}/images/a-night-at-the-opera-640.jpg", server.url)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn image_size_follows_size_policy() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let cover = client(&server)
+        .with_size_policy(SizePolicy::Closest(200))
+        .find_track_cover("Bohemian Rhapsody", &["Queen"], "A Night at the Opera")
+        .await?;
+
+    assert_eq!(
+        cover.image.url,
+        format!("{}/images/a-night-at-the-opera-300.jpg", server.url)
+    );
+    assert_eq!(cover.image.width, Some(300));
+    assert_eq!(cover.images.len(), 3);
+
+    let err = client(&server)
+        .with_size_policy(SizePolicy::Min(1000))
+        .find_track_cover("Bohemian Rhapsody", &["Queen"], "A Night at the Opera")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::NoMatch(_)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn match_includes_query_and_ranked_candidates() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let cover = client(&server)
+        .find_track_cover("Bohemian Rhapsody", &["Queen"], "A Night at the Opera")
+        .await?;
+
+    assert_eq!(cover.id.as_deref(), Some("3"));
+    assert_eq!(cover.query, "track:Bohemian Rhapsody artist:Queen");
+    let ids: Vec<_> = cover
+        .candidates
+        .iter()
+        .map(|candidate| candidate.id.as_deref().unwrap())
+        .collect();
+    assert_eq!(ids[0], "3");
+    assert_eq!(ids.len(), 4);
+    assert_eq!(cover.candidates[0].score, 0);
+    assert!(cover
+        .candidates
+        .windows(2)
+        .all(|pair| pair[0].score <= pair[1].score));
+    Ok(())
+}
+
+#[tokio::test]
+async fn best_matching_album_is_chosen() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let image_url = client(&server)
+        .get_image_url_for_album("A Night at the Opera", "Queen")
+        .await?;
+
+    assert_eq!(
+        image_url,
+        format!("{}/images/a-night-at-the-opera-640.jpg", server.url)
+    );
+    let requests = server.requests_to("/v1/search");
+    assert!(requests[0]
+        .query
+        .contains("album%3AA%20Night%20at%20the%20Opera"));
+    assert!(requests[0].query.contains("type=album"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn image_url_is_read_from_file_tags() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let music = tempfile::tempdir()?;
+    let track = music.path().join("01 Bohemian Rhapsody.mp3");
+    common::write_track(&track, "Bohemian Rhapsody", "Queen", "Greatest Hits");
+
+    let image_url = client(&server).get_image_url_from_filename(&track).await?;
+
+    assert_eq!(
+        image_url,
+        format!("{}/images/greatest-hits-640.jpg", server.url)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn untagged_file_is_rejected() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let music = tempfile::tempdir()?;
+    let notes = music.path().join("notes.txt");
+    fs::write(&notes, "not music")?;
+
+    let err = client(&server)
+        .get_image_url_from_filename(&notes)
+        .await
+        .unwrap_err();
+
+    assert!(matches!(err, Error::InvalidFiletype(_)));
+    assert!(server.requests_to("/v1/search").is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn image_is_downloaded() -> Result<()> {
+    let server = FakeSpotify::start().await;
+    let client = client(&server);
+    let image_url = client
+        .get_image_url_for_track("Bohemian Rhapsody", &["Queen"], "A Night at the Opera")
+        .await?;
+
+    assert_eq!(
+        client.download_image(&image_url).await?.data,
+        common::IMAGE_DATA
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn downloads_that_are_not_images_are_rejected() {
+    let server = FakeSpotify::with_handler(|request| match request.path.as_str() {
+        "/images/html.jpg" => Response::new(200, "text/html", "<html>Not found</html>"),
+        "/images/truncated.jpg" => Response::new(
+            200,
+            "image/jpeg",
+            &common::IMAGE_DATA[..common::IMAGE_DATA.len() / 2],
+        ),
+        _ => common::default_response(request),
+    })
+    .await;
+    let client = client(&server);
+
+    for path in ["/images/html.jpg", "/images/truncated.jpg"] {
+        let err = client
+            .download_image(&format!("{}{path}", server.url))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidImage(_)), "{path}: {err}");
+    }
+}
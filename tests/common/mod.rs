@@ -0,0 +1,302 @@
+//! A minimal in-process HTTP server that stands in for the Spotify accounts service and Web API,
+//! and for MusicBrainz, the Cover Art Archive, the iTunes Search API and Deezer
+
+#![allow(dead_code)]
+
+use id3::TagLike;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+pub const ACCESS_TOKEN: &str = "fake-access-token";
+/// Stands for the server's URL in response bodies, so that fixtures can contain absolute URLs
+pub const SERVER_URL: &str = "{server}";
+pub const IMAGE_DATA: &[u8] = include_bytes!("../fixtures/cover.jpg");
+pub const SEARCH_TRACKS: &str = include_str!("../fixtures/search_tracks.json");
+pub const SEARCH_ALBUMS: &str = include_str!("../fixtures/search_albums.json");
+pub const SEARCH_EMPTY: &str = include_str!("../fixtures/search_empty.json");
+pub const SEARCH_MALFORMED: &str = include_str!("../fixtures/search_malformed.json");
+pub const SEARCH_NO_IMAGES: &str = include_str!("../fixtures/search_no_images.json");
+pub const MUSICBRAINZ_RECORDINGS: &str = include_str!("../fixtures/musicbrainz_recordings.json");
+pub const MUSICBRAINZ_RELEASES: &str = include_str!("../fixtures/musicbrainz_releases.json");
+pub const MUSICBRAINZ_RELEASE: &str = include_str!("../fixtures/musicbrainz_release.json");
+pub const COVER_ART_OPERA: &str = include_str!("../fixtures/cover_art_opera.json");
+pub const COVER_ART_HITS: &str = include_str!("../fixtures/cover_art_hits.json");
+pub const ITUNES_SONGS: &str = include_str!("../fixtures/itunes_songs.json");
+pub const ITUNES_ALBUMS: &str = include_str!("../fixtures/itunes_albums.json");
+pub const DEEZER_TRACKS: &str = include_str!("../fixtures/deezer_tracks.json");
+pub const DEEZER_ALBUMS: &str = include_str!("../fixtures/deezer_albums.json");
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// Path without the query string
+    pub path: String,
+    pub query: String,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(|value| value.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Close the connection halfway through the body, like a dropped connection
+    pub cut_off: bool,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body: body.into(),
+            cut_off: false,
+        }
+    }
+
+    pub fn json(status: u16, body: &str) -> Self {
+        Self::new(status, "application/json", body)
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn cut_off(mut self) -> Self {
+        self.cut_off = true;
+        self
+    }
+}
+
+type Handler = dyn Fn(&Request) -> Response + Send + Sync;
+
+pub struct FakeSpotify {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl FakeSpotify {
+    /// Starts a server that answers token, search and image requests with the canned fixtures
+    pub async fn start() -> Self {
+        Self::with_handler(default_response).await
+    }
+
+    /// Starts a server that answers every request with `handler`
+    pub async fn with_handler(
+        handler: impl Fn(&Request) -> Response + Send + Sync + 'static,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Should be able to bind to a local port");
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        let recorded = requests.clone();
+        let server_url = url.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let url = server_url.clone();
+                let handler = handler.clone();
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    let _ = serve(stream, &url, handler.as_ref(), &recorded).await;
+                });
+            }
+        });
+
+        Self { url, requests }
+    }
+
+    /// All requests received so far, in order
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn requests_to(&self, path: &str) -> Vec<Request> {
+        self.requests()
+            .into_iter()
+            .filter(|request| request.path == path)
+            .collect()
+    }
+}
+
+/// The happy path: a valid token, the track or album fixture for any search, and image bytes for
+/// any image
+pub fn default_response(request: &Request) -> Response {
+    match request.path.as_str() {
+        "/api/token" => token_response(),
+        "/v1/search" => {
+            if request.header("authorization") != Some(&format!("Bearer {ACCESS_TOKEN}")) {
+                return Response::json(
+                    401,
+                    r#"{"error":{"status":401,"message":"Invalid access token"}}"#,
+                );
+            }
+            if request.query.contains("type=album") {
+                return Response::json(200, SEARCH_ALBUMS);
+            }
+            Response::json(200, SEARCH_TRACKS)
+        }
+        path if path.starts_with("/images/") => Response::new(200, "image/jpeg", IMAGE_DATA),
+        _ => Response::json(404, r#"{"error":{"status":404,"message":"Not found"}}"#),
+    }
+}
+
+/// The happy path, except that every search returns `search_results`
+pub fn with_search_results(search_results: &'static str) -> impl Fn(&Request) -> Response {
+    move |request| match request.path.as_str() {
+        "/v1/search" => Response::json(200, search_results),
+        _ => default_response(request),
+    }
+}
+
+/// MusicBrainz and the Cover Art Archive: `rel-opera` and `rel-hits` have covers, other releases
+/// don't, and only `rel-opera` can be looked up by its ID
+pub fn musicbrainz_response(request: &Request) -> Response {
+    let not_found = || Response::json(404, r#"{"error":"Not Found"}"#);
+    match request.path.as_str() {
+        "/ws/2/recording" => Response::json(200, MUSICBRAINZ_RECORDINGS),
+        "/ws/2/release" => Response::json(200, MUSICBRAINZ_RELEASES),
+        "/ws/2/release/rel-opera" => Response::json(200, MUSICBRAINZ_RELEASE),
+        "/release/rel-opera" => Response::json(200, COVER_ART_OPERA),
+        "/release/rel-hits" => Response::json(200, COVER_ART_HITS),
+        path if path.starts_with("/images/") => Response::new(200, "image/jpeg", IMAGE_DATA),
+        _ => not_found(),
+    }
+}
+
+/// The iTunes Search API, with artwork served under `/images/`
+pub fn itunes_response(request: &Request) -> Response {
+    match request.path.as_str() {
+        "/search" if request.query.contains("entity=album") => Response::json(200, ITUNES_ALBUMS),
+        "/search" => Response::json(200, ITUNES_SONGS),
+        path if path.starts_with("/images/") => Response::new(200, "image/jpeg", IMAGE_DATA),
+        _ => Response::json(404, r#"{"errorMessage":"Not Found"}"#),
+    }
+}
+
+/// The Deezer API, with covers served under `/images/`
+pub fn deezer_response(request: &Request) -> Response {
+    match request.path.as_str() {
+        "/search/track" => Response::json(200, DEEZER_TRACKS),
+        "/search/album" => Response::json(200, DEEZER_ALBUMS),
+        path if path.starts_with("/images/") => Response::new(200, "image/jpeg", IMAGE_DATA),
+        _ => Response::json(
+            200,
+            r#"{"error":{"type":"DataException","message":"no data","code":800}}"#,
+        ),
+    }
+}
+
+/// Creates an audio file at `path` that only contains an ID3 tag with the given fields
+pub fn write_track(path: &Path, title: &str, artist: &str, album: &str) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::File::create(path).unwrap();
+    let mut tag = id3::Tag::new();
+    tag.set_title(title);
+    tag.set_artist(artist);
+    tag.set_album(album);
+    tag.write_to_path(path, id3::Version::Id3v24).unwrap();
+}
+
+pub fn token_response() -> Response {
+    Response::json(
+        200,
+        &format!(r#"{{"access_token":"{ACCESS_TOKEN}","token_type":"Bearer","expires_in":3600}}"#),
+    )
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    url: &str,
+    handler: &Handler,
+    recorded: &Mutex<Vec<Request>>,
+) -> std::io::Result<()> {
+    let mut buffer = Vec::new();
+    let header_end = loop {
+        let mut chunk = [0u8; 4096];
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let target = request_line.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let headers: HashMap<_, _> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let content_length: usize = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    while buffer.len() < header_end + content_length {
+        let mut chunk = [0u8; 4096];
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+
+    let request = Request {
+        method,
+        path: path.to_string(),
+        query: query.to_string(),
+        headers,
+        body: String::from_utf8_lossy(&buffer[header_end..]).to_string(),
+    };
+    recorded.lock().unwrap().push(request.clone());
+
+    let mut response = handler(&request);
+    if let Ok(body) = std::str::from_utf8(&response.body) {
+        response.body = body.replace(SERVER_URL, url).into_bytes();
+    }
+    let reason = reqwest::StatusCode::from_u16(response.status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("Unknown");
+    let mut head = format!(
+        "HTTP/1.1 {} {reason}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.body.len()
+    );
+    for (name, value) in response.headers.iter() {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes()).await?;
+    let body = if response.cut_off {
+        &response.body[..response.body.len() / 2]
+    } else {
+        &response.body
+    };
+    stream.write_all(body).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,277 @@
+mod common;
+
+use anyhow::Result;
+use common::FakeSpotify;
+use spotify_image_search::{
+    AlbumInfo, CoverFinder, CoverProvider, DeezerClient, Error, ItunesClient, MusicBrainzClient,
+    RetryPolicy, SizePolicy, TrackInfo,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+fn musicbrainz(server: &FakeSpotify) -> MusicBrainzClient {
+    MusicBrainzClient::new()
+        .with_api_url(&server.url)
+        .with_cover_art_url(&server.url)
+}
+
+/// Retries once right away
+fn deezer(server: &FakeSpotify) -> DeezerClient {
+    DeezerClient::new()
+        .with_api_url(&server.url)
+        .with_retry_policy(RetryPolicy {
+            max_retries: 1,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        })
+}
+
+const DEEZER_QUOTA_ERROR: &str =
+    r#"{"error":{"type":"Exception","message":"Quota limit exceeded","code":4}}"#;
+
+fn finder(provider: impl CoverProvider + 'static) -> CoverFinder {
+    CoverFinder::new(vec![Box::new(provider)])
+}
+
+fn track(release_id: Option<&str>) -> TrackInfo {
+    TrackInfo {
+        title: "Bohemian Rhapsody".to_string(),
+        artists: vec!["Queen".to_string()],
+        album: "A Night at the Opera".to_string(),
+        album_artist: None,
+        musicbrainz_release_id: release_id.map(String::from),
+    }
+}
+
+#[tokio::test]
+async fn musicbrainz_finds_cover_art_of_searched_track() -> Result<()> {
+    let server = FakeSpotify::with_handler(common::musicbrainz_response).await;
+    let cover = finder(musicbrainz(&server))
+        .find_track_cover(&track(None))
+        .await?;
+
+    assert_eq!(cover.provider, "musicbrainz");
+    assert_eq!(cover.album, "A Night at the Opera");
+    assert_eq!(cover.id.as_deref(), Some("rec-opera"));
+    assert_eq!(
+        cover.image.url,
+        format!("{}/images/caa-opera-1200.jpg", server.url)
+    );
+    // The release without cover art is left out
+    assert_eq!(cover.candidates.len(), 2);
+    assert_eq!(server.requests_to("/release/rel-single").len(), 1);
+
+    let requests = server.requests_to("/ws/2/recording");
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0]
+        .query
+        .contains("recording%3A%22Bohemian%20Rhapsody%22%20AND%20artist%3A%22Queen%22"));
+    assert!(requests[0]
+        .header("user-agent")
+        .unwrap()
+        .starts_with("spotify-image-search/"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn musicbrainz_original_is_chosen_by_size_policy() -> Result<()> {
+    let server = FakeSpotify::with_handler(common::musicbrainz_response).await;
+    let album = AlbumInfo {
+        album: "A Night at the Opera".to_string(),
+        album_artist: "Queen".to_string(),
+        musicbrainz_release_id: None,
+    };
+    let cover = finder(musicbrainz(&server))
+        .with_size_policy(SizePolicy::Original)
+        .find_album_cover(&album)
+        .await?;
+
+    assert_eq!(cover.id.as_deref(), Some("rel-opera"));
+    assert_eq!(
+        cover.image.url,
+        format!("{}/images/caa-opera.jpg", server.url)
+    );
+    assert_eq!(cover.images.len(), 4);
+    Ok(())
+}
+
+#[tokio::test]
+async fn musicbrainz_release_id_is_used_instead_of_searching() -> Result<()> {
+    let server = FakeSpotify::with_handler(common::musicbrainz_response).await;
+    let cover = finder(musicbrainz(&server))
+        .find_track_cover(&track(Some("rel-opera")))
+        .await?;
+
+    assert_eq!(cover.id.as_deref(), Some("rel-opera"));
+    assert_eq!(cover.query, "reid:rel-opera");
+    assert_eq!(cover.score, 0);
+    assert!(server.requests_to("/ws/2/recording").is_empty());
+
+    // Unknown IDs fall back to searching
+    let cover = finder(musicbrainz(&server))
+        .find_track_cover(&track(Some("rel-unknown")))
+        .await?;
+    assert_eq!(cover.id.as_deref(), Some("rec-opera"));
+    assert_eq!(server.requests_to("/ws/2/recording").len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn musicbrainz_gets_at_most_one_request_per_second() -> Result<()> {
+    let server = FakeSpotify::with_handler(common::musicbrainz_response).await;
+    let finder = finder(musicbrainz(&server).with_rate_limit(0.0));
+
+    let start = Instant::now();
+    finder.find_track_cover(&track(Some("rel-opera"))).await?;
+    finder.find_track_cover(&track(Some("rel-opera"))).await?;
+
+    assert_eq!(server.requests_to("/ws/2/release/rel-opera").len(), 2);
+    assert!(start.elapsed() >= Duration::from_millis(900));
+    Ok(())
+}
+
+#[tokio::test]
+async fn musicbrainz_image_is_downloaded() -> Result<()> {
+    let server = FakeSpotify::with_handler(common::musicbrainz_response).await;
+    let finder = finder(musicbrainz(&server));
+    let cover = finder.find_track_cover(&track(None)).await?;
+
+    let downloaded = finder.download_image(&cover, &cover.image.url).await?;
+
+    assert_eq!(downloaded.data, common::IMAGE_DATA);
+    Ok(())
+}
+
+#[tokio::test]
+async fn itunes_artwork_is_rewritten_to_larger_sizes() -> Result<()> {
+    let server = FakeSpotify::with_handler(common::itunes_response).await;
+    let cover = finder(ItunesClient::new().with_api_url(&server.url))
+        .find_track_cover(&track(None))
+        .await?;
+
+    assert_eq!(cover.provider, "itunes");
+    assert_eq!(cover.id.as_deref(), Some("1003"));
+    assert_eq!(cover.album, "A Night at the Opera");
+    assert_eq!(
+        cover.image.url,
+        format!("{}/images/itunes-opera/3000x3000bb.jpg", server.url)
+    );
+    assert_eq!(cover.images.len(), 4);
+    // The music video and the song without an album are left out
+    assert_eq!(cover.candidates.len(), 2);
+
+    let requests = server.requests_to("/search");
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].query.contains("entity=song"));
+    assert!(requests[0].query.contains("country=US"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn itunes_searches_albums_in_the_given_country() -> Result<()> {
+    let server = FakeSpotify::with_handler(common::itunes_response).await;
+    let album = AlbumInfo {
+        album: "A Night at the Opera".to_string(),
+        album_artist: "Queen".to_string(),
+        musicbrainz_release_id: None,
+    };
+    let cover = finder(
+        ItunesClient::new()
+            .with_api_url(&server.url)
+            .with_country("GB"),
+    )
+    .with_size_policy(SizePolicy::Closest(1400))
+    .find_album_cover(&album)
+    .await?;
+
+    assert_eq!(cover.id.as_deref(), Some("2003"));
+    assert_eq!(
+        cover.image.url,
+        format!("{}/images/itunes-opera/1400x1400bb.jpg", server.url)
+    );
+
+    let requests = server.requests_to("/search");
+    assert!(requests[0].query.contains("entity=album"));
+    assert!(requests[0].query.contains("country=GB"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn deezer_finds_cover_xl_of_searched_track() -> Result<()> {
+    let server = FakeSpotify::with_handler(common::deezer_response).await;
+    let cover = finder(DeezerClient::new().with_api_url(&server.url))
+        .find_track_cover(&track(None))
+        .await?;
+
+    assert_eq!(cover.provider, "deezer");
+    assert_eq!(cover.id.as_deref(), Some("3002"));
+    assert_eq!(cover.album, "A Night at the Opera");
+    assert_eq!(
+        cover.image.url,
+        format!("{}/images/deezer-opera/1000x1000.jpg", server.url)
+    );
+    // The track without an album is left out
+    assert_eq!(cover.candidates.len(), 2);
+
+    let requests = server.requests_to("/search/track");
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0]
+        .query
+        .contains("track%3A%22Bohemian%20Rhapsody%22%20artist%3A%22Queen%22"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn deezer_album_size_is_chosen_by_size_policy() -> Result<()> {
+    let server = FakeSpotify::with_handler(common::deezer_response).await;
+    let album = AlbumInfo {
+        album: "A Night at the Opera".to_string(),
+        album_artist: "Queen".to_string(),
+        musicbrainz_release_id: None,
+    };
+    let cover = finder(DeezerClient::new().with_api_url(&server.url))
+        .with_size_policy(SizePolicy::Min(300))
+        .find_album_cover(&album)
+        .await?;
+
+    assert_eq!(cover.id.as_deref(), Some("4002"));
+    assert_eq!(
+        cover.image.url,
+        format!("{}/images/deezer-opera/500x500.jpg", server.url)
+    );
+    assert_eq!(cover.images.len(), 4);
+    Ok(())
+}
+
+#[tokio::test]
+async fn deezer_quota_error_is_rate_limited() -> Result<()> {
+    let server =
+        FakeSpotify::with_handler(|_| common::Response::json(200, DEEZER_QUOTA_ERROR)).await;
+    let result = finder(deezer(&server)).find_track_cover(&track(None)).await;
+
+    assert!(
+        matches!(result, Err(Error::RateLimited { .. })),
+        "{result:?}"
+    );
+    assert_eq!(server.requests_to("/search/track").len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn deezer_quota_error_is_retried() -> Result<()> {
+    let searches = AtomicUsize::new(0);
+    let server = FakeSpotify::with_handler(move |request| {
+        if request.path == "/search/track" && searches.fetch_add(1, Ordering::SeqCst) == 0 {
+            return common::Response::json(200, DEEZER_QUOTA_ERROR);
+        }
+        common::deezer_response(request)
+    })
+    .await;
+    let cover = finder(deezer(&server))
+        .find_track_cover(&track(None))
+        .await?;
+
+    assert_eq!(cover.id.as_deref(), Some("3002"));
+    assert_eq!(server.requests_to("/search/track").len(), 2);
+    Ok(())
+}